@@ -0,0 +1,43 @@
+use crate::authentication_manager::ServiceAccount;
+use crate::prelude::*;
+
+const GOOGLE_API_KEY: &str = "GOOGLE_API_KEY";
+
+/// API key accepted by some Google APIs instead of OAuth tokens, such as Maps
+#[derive(Debug)]
+pub struct ApiKey {
+    key: String,
+}
+
+impl ApiKey {
+    pub(crate) fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+        }
+    }
+
+    /// Reads API key from `GOOGLE_API_KEY`
+    pub(crate) fn from_env() -> Result<Self, Error> {
+        let key = std::env::var(GOOGLE_API_KEY).map_err(|_| Error::ApiKeyMissing)?;
+        Ok(Self::new(&key))
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for ApiKey {
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        Err(Error::NoProjectId)
+    }
+
+    fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+        None
+    }
+
+    async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+        Err(Error::NoToken)
+    }
+
+    fn api_key(&self) -> Option<String> {
+        Some(self.key.clone())
+    }
+}
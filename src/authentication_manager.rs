@@ -1,10 +1,86 @@
+use crate::api_key::ApiKey;
+use crate::credentials_file;
+use crate::custom_service_account::{ApplicationCredentials, CustomServiceAccount};
+use crate::default_authorized_user::{DefaultAuthorizedUser, UserCredentials};
+use crate::default_service_account::DefaultServiceAccount;
+use crate::endpoints::Endpoints;
+use crate::gcloud_config::GCloudConfig;
+use crate::impersonated_service_account::ImpersonatedServiceAccount;
+use crate::metadata::{MetadataClient, MetadataServiceAccount};
 use crate::prelude::*;
+use crate::revoke;
+use crate::static_token::StaticToken;
+use crate::tokeninfo::{self, TokenInfo};
+use crate::types::{new_client, Credential, ProjectIdSource};
+use chrono::{DateTime, Utc};
+use hyper::header::HeaderValue;
+use std::sync::RwLock;
+
+const GOOGLE_CLOUD_PROJECT: &str = "GOOGLE_CLOUD_PROJECT";
+const GCLOUD_PROJECT: &str = "GCLOUD_PROJECT";
+const GOOGLE_CLOUD_QUOTA_PROJECT: &str = "GOOGLE_CLOUD_QUOTA_PROJECT";
+const USER_PROJECT_HEADER: &str = "x-goog-user-project";
 
 #[async_trait]
 pub trait ServiceAccount: Send + Sync {
     async fn project_id(&self, client: &HyperClient) -> Result<String, Error>;
     fn get_token(&self, scopes: &[&str]) -> Option<Token>;
     async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error>;
+
+    fn quota_project_id(&self) -> Option<String> {
+        None
+    }
+
+    async fn universe_domain(&self, _client: &HyperClient) -> Result<String, Error> {
+        Ok(Endpoints::DEFAULT_UNIVERSE_DOMAIN.to_string())
+    }
+
+    fn get_token_for_subject(&self, _subject: &str, _scopes: &[&str]) -> Option<Token> {
+        None
+    }
+
+    async fn refresh_token_for_subject(
+        &self,
+        _client: &HyperClient,
+        _subject: &str,
+        _scopes: &[&str],
+    ) -> Result<Token, Error> {
+        Err(Error::NoSubject)
+    }
+
+    fn get_id_token(&self, _audience: &str) -> Option<Token> {
+        None
+    }
+
+    async fn refresh_id_token(
+        &self,
+        _client: &HyperClient,
+        _audience: &str,
+    ) -> Result<Token, Error> {
+        Err(Error::NoIdToken)
+    }
+
+    fn get_self_signed_jwt(&self, _audience: &str) -> Result<Token, Error> {
+        Err(Error::NoSelfSignedJwt)
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Revokes tokens at the revocation endpoint `revoke_uri` and purges them from caches
+    async fn revoke(&self, _client: &HyperClient, _revoke_uri: &str) -> Result<(), Error> {
+        Err(Error::NoRevocation)
+    }
+
+    /// Returns cached token for the scopes if it is still valid, otherwise requests a new one
+    async fn get_valid_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
+        let token = self.get_token(scopes);
+        if let Some(token) = token.filter(|token| !token.has_expired()) {
+            return Ok(token);
+        }
+        self.refresh_token(client, scopes).await
+    }
 }
 
 /// Authentication manager is responsible for caching and obtaing credentials for the required scope
@@ -13,26 +89,716 @@ pub trait ServiceAccount: Send + Sync {
 pub struct AuthenticationManager {
     pub(crate) client: HyperClient,
     pub(crate) service_account: Box<dyn ServiceAccount>,
+    pub(crate) metadata: MetadataClient,
+    pub(crate) project_id: RwLock<Option<(String, ProjectIdSource)>>,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) endpoints: Endpoints,
+    pub(crate) verify_scopes: bool,
+    pub(crate) granted_scopes: RwLock<Vec<(Token, Vec<String>)>>,
 }
 
 impl AuthenticationManager {
+    pub(crate) fn new(client: HyperClient, service_account: Box<dyn ServiceAccount>) -> Self {
+        Self::with_endpoints(client, service_account, Endpoints::default())
+    }
+
+    pub(crate) fn with_endpoints(
+        client: HyperClient,
+        service_account: Box<dyn ServiceAccount>,
+        endpoints: Endpoints,
+    ) -> Self {
+        AuthenticationManager {
+            metadata: MetadataClient::new(client.clone(), endpoints.metadata_host()),
+            client,
+            service_account,
+            project_id: RwLock::new(None),
+            quota_project_id: None,
+            endpoints,
+            verify_scopes: false,
+            granted_scopes: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Creates authentication manager from credentials in JSON format
+    ///
+    /// Credentials are chosen by the `type` field in the same way as for `GOOGLE_APPLICATION_CREDENTIALS`.
+    pub async fn from_json(json: &str) -> Result<Self, Error> {
+        Self::from_slice(json.as_bytes()).await
+    }
+
+    /// Creates authentication manager from credentials in JSON format with endpoint overrides
+    pub async fn from_json_with_endpoints(json: &str, endpoints: Endpoints) -> Result<Self, Error> {
+        Self::from_slice_with_endpoints(json.as_bytes(), endpoints).await
+    }
+
+    /// Creates authentication manager from credentials in JSON format
+    ///
+    /// Credentials are chosen by the `type` field in the same way as for `GOOGLE_APPLICATION_CREDENTIALS`.
+    pub async fn from_slice(json: &[u8]) -> Result<Self, Error> {
+        Self::from_slice_with_endpoints(json, Endpoints::default()).await
+    }
+
+    /// Creates authentication manager from credentials in JSON format with endpoint overrides
+    pub async fn from_slice_with_endpoints(
+        json: &[u8],
+        endpoints: Endpoints,
+    ) -> Result<Self, Error> {
+        let client = new_client();
+        let (service_account, endpoints) =
+            credentials_file::from_slice(&client, &endpoints, json).await?;
+        Ok(AuthenticationManager::with_endpoints(
+            client,
+            service_account,
+            endpoints,
+        ))
+    }
+
+    /// Creates authentication manager from credentials file
+    ///
+    /// Credentials are chosen by the `type` field in the same way as for `GOOGLE_APPLICATION_CREDENTIALS`.
+    pub async fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
+        Self::from_file_with_endpoints(path, Endpoints::default()).await
+    }
+
+    /// Creates authentication manager from credentials file with endpoint overrides
+    pub async fn from_file_with_endpoints<T: AsRef<Path>>(
+        path: T,
+        endpoints: Endpoints,
+    ) -> Result<Self, Error> {
+        let client = new_client();
+        let (service_account, endpoints) =
+            credentials_file::from_file(&client, &endpoints, path).await?;
+        Ok(AuthenticationManager::with_endpoints(
+            client,
+            service_account,
+            endpoints,
+        ))
+    }
+
+    /// Creates authentication manager from service account key
+    pub async fn from_application_credentials(
+        credentials: ApplicationCredentials,
+    ) -> Result<Self, Error> {
+        Self::from_application_credentials_with_endpoints(credentials, Endpoints::default()).await
+    }
+
+    /// Creates authentication manager from service account key with endpoint overrides
+    pub async fn from_application_credentials_with_endpoints(
+        credentials: ApplicationCredentials,
+        endpoints: Endpoints,
+    ) -> Result<Self, Error> {
+        let endpoints = endpoints.for_universe(credentials.universe_domain.as_deref())?;
+        let service_account = CustomServiceAccount::from_credentials(credentials, &endpoints)?;
+        Ok(AuthenticationManager::with_endpoints(
+            new_client(),
+            Box::new(service_account),
+            endpoints,
+        ))
+    }
+
+    /// Creates authentication manager using API key, accepted by some Google APIs instead of OAuth tokens
+    ///
+    /// Access tokens can't be obtained with API key, use `credential` or `authorize_request` instead.
+    pub fn from_api_key(key: &str) -> Self {
+        AuthenticationManager::new(new_client(), Box::new(ApiKey::new(key)))
+    }
+
+    /// Creates authentication manager using API key from `GOOGLE_API_KEY`
+    pub fn from_api_key_env() -> Result<Self, Error> {
+        Ok(AuthenticationManager::new(
+            new_client(),
+            Box::new(ApiKey::from_env()?),
+        ))
+    }
+
+    /// Creates authentication manager using access token obtained outside of the application
+    ///
+    /// The token is used for all scopes, requests fail with `Error::StaticTokenExpired` after `expires_at`.
+    pub fn from_access_token(access_token: &str, expires_at: Option<DateTime<Utc>>) -> Self {
+        AuthenticationManager::new(
+            new_client(),
+            Box::new(StaticToken::new(access_token, expires_at)),
+        )
+    }
+
+    /// Creates authentication manager using access token read from file at `path`
+    ///
+    /// The file is re-read whenever its modification time changes, `expires_at` applies to every token read.
+    pub async fn from_access_token_file<T: AsRef<Path>>(
+        path: T,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self, Error> {
+        let token = StaticToken::from_file(path.as_ref().to_path_buf(), expires_at).await?;
+        Ok(AuthenticationManager::new(new_client(), Box::new(token)))
+    }
+
+    /// Creates authentication manager using the default service account of metadata server at `host`
+    ///
+    /// Useful for local metadata server emulators, `host` may include port.
+    pub async fn from_metadata_host(host: &str) -> Result<Self, Error> {
+        Self::from_metadata_service_account(DefaultServiceAccount::DEFAULT_ACCOUNT, Some(host))
+            .await
+    }
+
+    /// Creates authentication manager using service account of metadata server identified by email or alias
+    ///
+    /// Instances can have several service accounts attached, `default` is used by `init`. Metadata server
+    /// at `host` is used for all metadata requests, `GCE_METADATA_HOST` or the default host if not provided.
+    pub async fn from_metadata_service_account(
+        account: &str,
+        host: Option<&str>,
+    ) -> Result<Self, Error> {
+        let client = new_client();
+        let endpoints = match host {
+            Some(host) => Endpoints::new().with_metadata_host(host),
+            None => Endpoints::new(),
+        };
+        let service_account =
+            DefaultServiceAccount::with_options(&client, &endpoints.metadata_host(), account)
+                .await?;
+        Ok(AuthenticationManager::with_endpoints(
+            client,
+            Box::new(service_account),
+            endpoints,
+        ))
+    }
+
+    /// Lists service accounts attached to the instance with scopes granted to them
+    ///
+    /// Can be used to check at startup that the required scopes are available.
+    pub async fn metadata_service_accounts(&self) -> Result<Vec<MetadataServiceAccount>, Error> {
+        self.metadata.service_accounts().await
+    }
+
+    /// Client of the GCP instance metadata server sharing HTTP client with the authentication manager
+    pub fn metadata(&self) -> &MetadataClient {
+        &self.metadata
+    }
+
+    /// Creates authentication manager from authorized user credentials
+    ///
+    /// Refresh token is exchanged for access token immediately to validate the credentials.
+    pub async fn from_user_credentials(credentials: UserCredentials) -> Result<Self, Error> {
+        Self::from_user_credentials_with_endpoints(credentials, Endpoints::default()).await
+    }
+
+    /// Creates authentication manager from authorized user credentials with endpoint overrides
+    pub async fn from_user_credentials_with_endpoints(
+        credentials: UserCredentials,
+        endpoints: Endpoints,
+    ) -> Result<Self, Error> {
+        let client = new_client();
+        let user =
+            DefaultAuthorizedUser::from_credentials(&client, &endpoints, credentials, None).await?;
+        Ok(AuthenticationManager::with_endpoints(
+            client,
+            Box::new(user),
+            endpoints,
+        ))
+    }
+
+    /// Creates authentication manager from authorized user credentials file
+    ///
+    /// Refresh token rotated by the server is written back to the file, other fields are kept. The file is
+    /// replaced atomically and keeps its permissions. Unlike this, `init` keeps rotated tokens only in memory.
+    pub async fn from_user_credentials_file<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let content = tokio::fs::read(&path)
+            .await
+            .map_err(Error::UserProfilePath)?;
+        let credentials = serde_json::from_slice(&content).map_err(Error::UserProfileFormat)?;
+        let client = new_client();
+        let user = DefaultAuthorizedUser::from_credentials(
+            &client,
+            &Endpoints::default(),
+            credentials,
+            Some(path),
+        )
+        .await?;
+        Ok(AuthenticationManager::new(client, Box::new(user)))
+    }
+
     /// Requests Bearer token for the provided scope
     ///
     /// Token can be used in the request authorization header in format "Bearer {token}"
     pub async fn get_token(&self, scopes: &[&str]) -> Result<Token, Error> {
-        let token = self.service_account.get_token(scopes);
+        self.get_valid_token(None, scopes).await
+    }
+
+    /// Returns cached token of the subject if it is still valid, otherwise requests a new one
+    ///
+    /// With scope verification, every returned token must grant the requested scopes. Granted scopes are
+    /// looked up once per token, a cached token not granting them is refreshed and checked again.
+    async fn get_valid_token(
+        &self,
+        subject: Option<&str>,
+        scopes: &[&str],
+    ) -> Result<Token, Error> {
+        let verify = self.verify_scopes && !scopes.is_empty();
+        let token = match subject {
+            Some(subject) => self.service_account.get_token_for_subject(subject, scopes),
+            None => self.service_account.get_token(scopes),
+        };
+        if let Some(token) = token.filter(|token| !token.has_expired()) {
+            if !verify {
+                return Ok(token);
+            }
+            match self.check_granted_scopes(&token, scopes).await {
+                Ok(()) => return Ok(token),
+                Err(Error::ScopesNotGranted(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let token = match subject {
+            Some(subject) => {
+                self.service_account
+                    .refresh_token_for_subject(&self.client, subject, scopes)
+                    .await?
+            }
+            None => {
+                self.service_account
+                    .refresh_token(&self.client, scopes)
+                    .await?
+            }
+        };
+        if verify {
+            self.check_granted_scopes(&token, scopes).await?;
+        }
+        Ok(token)
+    }
+
+    /// Checks that the token grants the requested scopes, asking the tokeninfo endpoint for new tokens
+    async fn check_granted_scopes(&self, token: &Token, scopes: &[&str]) -> Result<(), Error> {
+        let granted = self
+            .granted_scopes
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(verified, _)| verified.as_str() == token.as_str())
+            .map(|(_, granted)| granted.clone());
+        let granted = match granted {
+            Some(granted) => granted,
+            None => {
+                let info = tokeninfo::token_info(
+                    &self.client,
+                    &self.endpoints.tokeninfo_uri(),
+                    token,
+                    tokeninfo::ACCESS_TOKEN,
+                )
+                .await?;
+                let mut granted_scopes = self.granted_scopes.write().unwrap();
+                granted_scopes.retain(|(token, _)| !token.has_expired());
+                granted_scopes.push((token.clone(), info.scopes.clone()));
+                info.scopes
+            }
+        };
+        let missing: Vec<_> = scopes
+            .iter()
+            .filter(|scope| !granted.iter().any(|granted| granted == *scope))
+            .map(|scope| (*scope).to_string())
+            .collect();
+        if !missing.is_empty() {
+            log::error!("Token doesn't grant scopes {:?}", missing);
+            return Err(Error::ScopesNotGranted(missing));
+        }
+        Ok(())
+    }
+
+    /// Enables debug mode checking that each freshly issued token grants the requested scopes
+    ///
+    /// Every freshly issued token is checked with the tokeninfo endpoint before it is returned, including
+    /// tokens for a subject, not intended for production.
+    pub fn with_scope_verification(mut self) -> AuthenticationManager {
+        self.verify_scopes = true;
+        self
+    }
+
+    /// Requests information about access or ID token, such as account email and granted scopes
+    ///
+    /// Useful for debugging requests failing with 403. JWTs are introspected as ID tokens first, and as
+    /// access tokens if rejected, since access tokens may be self-signed JWTs.
+    pub async fn token_info(&self, token: &Token) -> Result<TokenInfo, Error> {
+        let tokeninfo_uri = self.endpoints.tokeninfo_uri();
+        if token.as_str().split('.').count() == 3 {
+            let info =
+                tokeninfo::token_info(&self.client, &tokeninfo_uri, token, tokeninfo::ID_TOKEN)
+                    .await;
+            match info {
+                Err(Error::ServerUnavailable) => {
+                    log::debug!("Token rejected as ID token, retrying as access token")
+                }
+                info => return info,
+            }
+        }
+        tokeninfo::token_info(&self.client, &tokeninfo_uri, token, tokeninfo::ACCESS_TOKEN).await
+    }
+
+    /// Requests credential to be attached to requests, Bearer token for the scopes or API key
+    ///
+    /// Allows using the same code for APIs accepting both kinds of credentials.
+    pub async fn credential(&self, scopes: &[&str]) -> Result<Credential, Error> {
+        if let Some(key) = self.service_account.api_key() {
+            return Ok(Credential::ApiKey(key));
+        }
+        Ok(Credential::Token(self.get_token(scopes).await?))
+    }
+
+    /// Requests Bearer token for the provided scope on behalf of the Workspace user `subject`
+    ///
+    /// Requires service account key with domain-wide delegation granted in the Workspace admin console.
+    pub async fn get_token_for_subject(
+        &self,
+        subject: &str,
+        scopes: &[&str],
+    ) -> Result<Token, Error> {
+        self.get_valid_token(Some(subject), scopes).await
+    }
+
+    /// Requests OIDC ID token signed by Google for the provided audience
+    ///
+    /// ID tokens are required by services such as Cloud Run or IAP which authenticate the caller
+    /// by verifying that the token was issued for them. Tokens are cached per audience.
+    pub async fn get_id_token(&self, audience: &str) -> Result<Token, Error> {
+        let token = self.service_account.get_id_token(audience);
         if let Some(token) = token.filter(|token| !token.has_expired()) {
             return Ok(token);
         }
         self.service_account
-            .refresh_token(&self.client, scopes)
+            .refresh_id_token(&self.client, audience)
             .await
     }
 
+    /// Requests self-signed JWT usable as Bearer token for the API audience, e.g. `https://pubsub.googleapis.com/`
+    ///
+    /// The JWT is signed locally with service account key, avoiding the round trip to the token endpoint.
+    /// Only available when authenticating with a service account key.
+    pub fn get_self_signed_jwt(&self, audience: &str) -> Result<Token, Error> {
+        self.service_account.get_self_signed_jwt(audience)
+    }
+
+    /// Universe domain of the credentials, `googleapis.com` unless the credentials belong to another universe
+    pub async fn universe_domain(&self) -> Result<String, Error> {
+        self.service_account.universe_domain(&self.client).await
+    }
+
+    /// Revokes tokens of the credentials and purges them from caches, e.g. on logout
+    ///
+    /// Refresh token of authorized user is revoked together with its access tokens, cached access tokens
+    /// of service account key are revoked one by one, skipping expired ones. Cached tokens are purged even if
+    /// revocation fails.
+    pub async fn revoke(&self) -> Result<(), Error> {
+        let revoke_uri = self.endpoints.revoke_uri();
+        self.service_account.revoke(&self.client, &revoke_uri).await
+    }
+
+    /// Revokes single access or refresh token at the revocation endpoint
+    pub async fn revoke_token(&self, token: &str) -> Result<(), Error> {
+        revoke::revoke_token(&self.client, &self.endpoints.revoke_uri(), token).await
+    }
+
     /// Request the project ID for the authenticating account
     ///
-    /// This is only available for service account-based authentication methods.
+    /// The project ID is resolved from the first available source in the following order: `GOOGLE_CLOUD_PROJECT`
+    /// or `GCLOUD_PROJECT` environment variable, the credentials, `quota_project_id` of application default
+    /// credentials, `core/project` of the active gcloud configuration and the GCP instance metadata server.
+    /// The result is cached.
     pub async fn project_id(&self) -> Result<String, Error> {
-        self.service_account.project_id(&self.client).await
+        Ok(self.project_id_with_source().await?.0)
+    }
+
+    /// Request the project ID for the authenticating account with the source it was resolved from
+    pub async fn project_id_with_source(&self) -> Result<(String, ProjectIdSource), Error> {
+        let cached = self.project_id.read().unwrap().clone();
+        if let Some(project_id) = cached {
+            return Ok(project_id);
+        }
+        let project_id = self.resolve_project_id().await?;
+        log::debug!("Resolved project ID from {:?}", project_id.1);
+        *self.project_id.write().unwrap() = Some(project_id.clone());
+        Ok(project_id)
+    }
+
+    async fn resolve_project_id(&self) -> Result<(String, ProjectIdSource), Error> {
+        let env_project_id = [GOOGLE_CLOUD_PROJECT, GCLOUD_PROJECT]
+            .iter()
+            .find_map(|variable| std::env::var(variable).ok());
+        self.resolve_project_id_with(env_project_id).await
+    }
+
+    /// Resolves project ID with the value of the environment variables given explicitly
+    async fn resolve_project_id_with(
+        &self,
+        env_project_id: Option<String>,
+    ) -> Result<(String, ProjectIdSource), Error> {
+        if let Some(project_id) = env_project_id {
+            return Ok((project_id, ProjectIdSource::Environment));
+        }
+        if let Ok(project_id) = self.service_account.project_id(&self.client).await {
+            return Ok((project_id, ProjectIdSource::Credentials));
+        }
+        if let Some(project_id) = self.service_account.quota_project_id() {
+            return Ok((project_id, ProjectIdSource::QuotaProject));
+        }
+        if let Some(project_id) = GCloudConfig::load().await.ok().and_then(|x| x.project) {
+            return Ok((project_id, ProjectIdSource::GCloudConfig));
+        }
+        if let Ok(project_id) = self.metadata.project_id().await {
+            return Ok((project_id, ProjectIdSource::MetadataServer));
+        }
+        Err(Error::ProjectIdNotFound)
+    }
+
+    /// Impersonate the target service account using the currently discovered credentials
+    ///
+    /// Tokens are minted by the IAM Credentials `generateAccessToken` endpoint. The current credentials
+    /// need `roles/iam.serviceAccountTokenCreator` on the target account, or on the first account of
+    /// the `delegates` chain. Token lifetime defaults to one hour if not provided.
+    pub fn impersonate(
+        self,
+        target_principal: &str,
+        delegates: &[&str],
+        lifetime: Option<chrono::Duration>,
+    ) -> AuthenticationManager {
+        let service_account = ImpersonatedServiceAccount::new(
+            self.service_account,
+            &self.endpoints,
+            target_principal,
+            delegates,
+            lifetime,
+        );
+        AuthenticationManager {
+            client: self.client,
+            service_account: Box::new(service_account),
+            metadata: self.metadata,
+            project_id: RwLock::new(None),
+            quota_project_id: self.quota_project_id,
+            endpoints: self.endpoints,
+            verify_scopes: self.verify_scopes,
+            granted_scopes: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Overrides the quota project of the credentials and `GOOGLE_CLOUD_QUOTA_PROJECT`
+    pub fn with_quota_project(mut self, quota_project_id: &str) -> AuthenticationManager {
+        self.quota_project_id = Some(quota_project_id.to_string());
+        self
+    }
+
+    /// Project used for quota and billing of requests
+    ///
+    /// Explicitly set quota project takes precedence over `GOOGLE_CLOUD_QUOTA_PROJECT` environment variable
+    /// and `quota_project_id` of the credentials.
+    pub fn quota_project_id(&self) -> Option<String> {
+        self.quota_project_id
+            .clone()
+            .or_else(|| std::env::var(GOOGLE_CLOUD_QUOTA_PROJECT).ok())
+            .or_else(|| self.service_account.quota_project_id())
+    }
+
+    /// Sets `Authorization` header with Bearer token for the scopes, or `x-goog-api-key` header with API key,
+    /// and `x-goog-user-project` header with the quota project, if available
+    pub async fn authorize_request<B: Send>(
+        &self,
+        request: &mut Request<B>,
+        scopes: &[&str],
+    ) -> Result<(), Error> {
+        let (name, value) = self.credential(scopes).await?.header();
+        let value = HeaderValue::from_str(&value).map_err(|_| Error::InvalidHeaderValue)?;
+        request.headers_mut().insert(name, value);
+        if let Some(quota_project_id) = self.quota_project_id() {
+            let quota_project_id =
+                HeaderValue::from_str(&quota_project_id).map_err(|_| Error::InvalidHeaderValue)?;
+            request
+                .headers_mut()
+                .insert(USER_PROJECT_HEADER, quota_project_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, EnvGuard};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Issues new token on every refresh and caches the last one
+    #[derive(Default)]
+    struct Counter {
+        refreshes: AtomicUsize,
+        token: RwLock<Option<Token>>,
+    }
+
+    #[async_trait]
+    impl ServiceAccount for Counter {
+        async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+            Err(Error::NoProjectId)
+        }
+
+        fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+            self.token.read().unwrap().clone()
+        }
+
+        async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+            let count = self.refreshes.fetch_add(1, Ordering::SeqCst) + 1;
+            let token = Token::new(format!("token-{}", count), None);
+            *self.token.write().unwrap() = Some(token.clone());
+            Ok(token)
+        }
+    }
+
+    /// Credentials with fixed project and quota project
+    struct Project {
+        project_id: Option<&'static str>,
+        quota_project_id: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl ServiceAccount for Project {
+        async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+            self.project_id
+                .map(str::to_string)
+                .ok_or(Error::NoProjectId)
+        }
+
+        fn quota_project_id(&self) -> Option<String> {
+            self.quota_project_id.map(str::to_string)
+        }
+
+        fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+            None
+        }
+
+        async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+            unreachable!("tokens are not requested")
+        }
+    }
+
+    #[tokio::test]
+    async fn project_id_resolution_order() {
+        let manager = |project_id, quota_project_id| {
+            AuthenticationManager::new(
+                new_client(),
+                Box::new(Project {
+                    project_id,
+                    quota_project_id,
+                }),
+            )
+        };
+
+        let resolved = manager(Some("credentials"), Some("quota"))
+            .resolve_project_id_with(Some("environment".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            ("environment".to_string(), ProjectIdSource::Environment)
+        );
+
+        let resolved = manager(Some("credentials"), Some("quota"))
+            .resolve_project_id_with(None)
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            ("credentials".to_string(), ProjectIdSource::Credentials)
+        );
+
+        let resolved = manager(None, Some("quota"))
+            .resolve_project_id_with(None)
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            ("quota".to_string(), ProjectIdSource::QuotaProject)
+        );
+    }
+
+    #[test]
+    fn quota_project_precedence() {
+        let mut env = EnvGuard::new();
+        let manager = || {
+            AuthenticationManager::new(
+                new_client(),
+                Box::new(Project {
+                    project_id: None,
+                    quota_project_id: Some("credentials"),
+                }),
+            )
+        };
+
+        env.remove(GOOGLE_CLOUD_QUOTA_PROJECT);
+        assert_eq!(manager().quota_project_id().as_deref(), Some("credentials"));
+
+        env.set(GOOGLE_CLOUD_QUOTA_PROJECT, "environment");
+        assert_eq!(manager().quota_project_id().as_deref(), Some("environment"));
+
+        let manager = manager().with_quota_project("builder");
+        assert_eq!(manager.quota_project_id().as_deref(), Some("builder"));
+    }
+
+    #[tokio::test]
+    async fn authorize_request_headers() {
+        let mut env = EnvGuard::new();
+        env.remove(GOOGLE_CLOUD_QUOTA_PROJECT);
+
+        let manager =
+            AuthenticationManager::from_access_token("access", None).with_quota_project("quota");
+        let mut request = Request::new(());
+        manager
+            .authorize_request(&mut request, &["scope-a"])
+            .await
+            .unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["authorization"], "Bearer access");
+        assert_eq!(headers[USER_PROJECT_HEADER], "quota");
+        assert!(headers.get("x-goog-api-key").is_none());
+
+        let manager = AuthenticationManager::from_api_key("key");
+        let mut request = Request::new(());
+        manager.authorize_request(&mut request, &[]).await.unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["x-goog-api-key"], "key");
+        assert!(headers.get("authorization").is_none());
+        assert!(headers.get(USER_PROJECT_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn token_failing_verification_is_not_served() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", r#"{"scope":"scope-b"}"#)),
+            Some(("200 OK", r#"{"scope":"scope-a scope-b"}"#)),
+            Some(("200 OK", r#"{"scope":"scope-a"}"#)),
+        ])
+        .await;
+        let endpoints = Endpoints::new().with_tokeninfo_uri(&format!("{}/tokeninfo", uri));
+        let manager = AuthenticationManager::with_endpoints(
+            new_client(),
+            Box::new(Counter::default()),
+            endpoints,
+        )
+        .with_scope_verification();
+
+        match manager.get_token(&["scope-a"]).await {
+            Err(Error::ScopesNotGranted(missing)) => assert_eq!(missing, vec!["scope-a"]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let token = manager.get_token(&["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "token-2");
+        let token = manager.get_token(&["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "token-2");
+
+        // The same token is returned for all scopes, so other scope sets are checked as well
+        let token = manager.get_token(&["scope-b"]).await.unwrap();
+        assert_eq!(token.as_str(), "token-2");
+        match manager.get_token(&["scope-c"]).await {
+            Err(Error::ScopesNotGranted(missing)) => assert_eq!(missing, vec!["scope-c"]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].line.contains("access_token=token-1"));
+        assert!(requests[1].line.contains("access_token=token-2"));
+        assert!(requests[2].line.contains("access_token=token-3"));
     }
 }
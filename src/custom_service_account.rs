@@ -1,75 +1,190 @@
 use crate::authentication_manager::ServiceAccount;
+use crate::endpoints::Endpoints;
+use crate::jwt::{Claims, JWTSigner, GRANT_TYPE};
 use crate::prelude::*;
+use crate::revoke;
+use crate::types::timestamp;
 use std::sync::RwLock;
-use tokio::fs;
 
 #[derive(Debug)]
 pub struct CustomServiceAccount {
-    tokens: RwLock<HashMap<Vec<String>, Token>>,
+    tokens: RwLock<HashMap<(Option<String>, Vec<String>), Token>>,
+    id_tokens: RwLock<HashMap<String, Token>>,
+    self_signed_tokens: RwLock<HashMap<String, Token>>,
     credentials: ApplicationCredentials,
+    token_uri: String,
 }
 
 impl CustomServiceAccount {
-    const GOOGLE_APPLICATION_CREDENTIALS: &'static str = "GOOGLE_APPLICATION_CREDENTIALS";
-
-    pub async fn new() -> Result<Self, Error> {
-        let path = std::env::var(Self::GOOGLE_APPLICATION_CREDENTIALS)
-            .map_err(|_| Error::AplicationProfileMissing)?;
-        let credentials = ApplicationCredentials::from_file(path).await?;
+    pub fn from_credentials(
+        credentials: ApplicationCredentials,
+        endpoints: &Endpoints,
+    ) -> Result<Self, Error> {
+        let endpoints = &endpoints.for_universe(credentials.universe_domain.as_deref())?;
         Ok(Self {
+            token_uri: endpoints.oauth_token_uri(Some(&credentials.token_uri)),
             credentials,
             tokens: RwLock::new(HashMap::new()),
+            id_tokens: RwLock::new(HashMap::new()),
+            self_signed_tokens: RwLock::new(HashMap::new()),
         })
     }
-}
 
-#[async_trait]
-impl ServiceAccount for CustomServiceAccount {
-    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
-        match &self.credentials.project_id {
-            Some(pid) => Ok(pid.clone()),
-            None => Err(Error::ProjectIdNotFound),
-        }
+    fn cached_token(&self, subject: Option<&str>, scopes: &[&str]) -> Option<Token> {
+        let key = (
+            subject.map(|x| x.to_string()),
+            scopes.iter().map(|x| x.to_string()).collect(),
+        );
+        self.tokens.read().unwrap().get(&key).cloned()
     }
 
-    fn get_token(&self, scopes: &[&str]) -> Option<Token> {
-        let key: Vec<_> = scopes.iter().map(|x| x.to_string()).collect();
-        self.tokens.read().unwrap().get(&key).cloned()
+    async fn refresh_cached_token(
+        &self,
+        client: &HyperClient,
+        subject: Option<&str>,
+        scopes: &[&str],
+    ) -> Result<Token, Error> {
+        let claims = Claims::new(&self.credentials, scopes, subject);
+        let token = self.request_token::<Token>(client, &claims).await?;
+        let key = (
+            subject.map(|x| x.to_string()),
+            scopes.iter().map(|x| (*x).to_string()).collect(),
+        );
+        self.tokens.write().unwrap().insert(key, token.clone());
+        Ok(token)
     }
 
-    async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
-        use crate::jwt::Claims;
-        use crate::jwt::JWTSigner;
-        use crate::jwt::GRANT_TYPE;
+    async fn request_token<T>(&self, client: &HyperClient, claims: &Claims<'_>) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
         use hyper::header;
         use url::form_urlencoded;
 
         let signer = JWTSigner::new(&self.credentials.private_key)?;
-
-        let claims = Claims::new(&self.credentials, scopes, None);
-        let signed = signer.sign_claims(&claims).map_err(Error::TLSError)?;
+        let signed = signer.sign_claims(claims).map_err(Error::TLSError)?;
         let rqbody = form_urlencoded::Serializer::new(String::new())
             .extend_pairs(&[("grant_type", GRANT_TYPE), ("assertion", signed.as_str())])
             .finish();
-        let request = hyper::Request::post(&self.credentials.token_uri)
+        let request = hyper::Request::post(&self.token_uri)
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
             .body(hyper::Body::from(rqbody))
             .unwrap();
         log::debug!("requesting token from service account: {:?}", request);
-        let token = client
+        client
             .request(request)
             .await
             .map_err(Error::OAuthConnectionError)?
-            .deserialize::<Token>()
+            .deserialize::<T>()
+            .await
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for CustomServiceAccount {
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        match &self.credentials.project_id {
+            Some(pid) => Ok(pid.clone()),
+            None => Err(Error::ProjectIdNotFound),
+        }
+    }
+
+    async fn universe_domain(&self, _: &HyperClient) -> Result<String, Error> {
+        Ok(self
+            .credentials
+            .universe_domain
+            .clone()
+            .unwrap_or_else(|| Endpoints::DEFAULT_UNIVERSE_DOMAIN.to_string()))
+    }
+
+    fn quota_project_id(&self) -> Option<String> {
+        self.credentials.quota_project_id.clone()
+    }
+
+    fn get_token(&self, scopes: &[&str]) -> Option<Token> {
+        self.cached_token(None, scopes)
+    }
+
+    async fn revoke(&self, client: &HyperClient, revoke_uri: &str) -> Result<(), Error> {
+        let tokens = self
+            .tokens
+            .write()
+            .unwrap()
+            .drain()
+            .map(|(_, x)| x)
+            .collect();
+        self.id_tokens.write().unwrap().clear();
+        self.self_signed_tokens.write().unwrap().clear();
+        revoke::revoke_tokens(client, revoke_uri, tokens).await
+    }
+
+    async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
+        self.refresh_cached_token(client, None, scopes).await
+    }
+
+    fn get_token_for_subject(&self, subject: &str, scopes: &[&str]) -> Option<Token> {
+        self.cached_token(Some(subject), scopes)
+    }
+
+    async fn refresh_token_for_subject(
+        &self,
+        client: &HyperClient,
+        subject: &str,
+        scopes: &[&str],
+    ) -> Result<Token, Error> {
+        self.refresh_cached_token(client, Some(subject), scopes)
+            .await
+    }
+
+    fn get_self_signed_jwt(&self, audience: &str) -> Result<Token, Error> {
+        let token = self
+            .self_signed_tokens
+            .read()
+            .unwrap()
+            .get(audience)
+            .cloned();
+        if let Some(token) = token.filter(|token| !token.has_expired()) {
+            return Ok(token);
+        }
+        let signer = JWTSigner::new(&self.credentials.private_key)?
+            .with_key_id(self.credentials.private_key_id.as_deref());
+        let claims = Claims::with_audience(&self.credentials, audience);
+        let signed = signer.sign_claims(&claims).map_err(Error::TLSError)?;
+        let token = Token::new(signed, Some(timestamp(claims.expiry())?));
+        self.self_signed_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), token.clone());
+        Ok(token)
+    }
+
+    fn get_id_token(&self, audience: &str) -> Option<Token> {
+        self.id_tokens.read().unwrap().get(audience).cloned()
+    }
+
+    async fn refresh_id_token(&self, client: &HyperClient, audience: &str) -> Result<Token, Error> {
+        let claims = Claims::with_target_audience(&self.credentials, audience);
+        let response = self
+            .request_token::<IdTokenResponse>(client, &claims)
             .await?;
-        let key = scopes.iter().map(|x| (*x).to_string()).collect();
-        self.tokens.write().unwrap().insert(key, token.clone());
+        let token = Token::from_jwt(response.id_token)?;
+        self.id_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), token.clone());
         Ok(token)
     }
 }
 
+#[derive(Deserialize, Debug)]
+struct IdTokenResponse {
+    id_token: String,
+}
+
+/// Service account key, as downloaded from IAM service in GCP console
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ApplicationCredentials {
+    /// type
     pub r#type: Option<String>,
     /// project_id
     pub project_id: Option<String>,
@@ -89,13 +204,153 @@ pub struct ApplicationCredentials {
     pub auth_provider_x509_cert_url: Option<String>,
     /// client_x509_cert_url
     pub client_x509_cert_url: Option<String>,
+    /// quota_project_id
+    pub quota_project_id: Option<String>,
+    /// universe_domain
+    pub universe_domain: Option<String>,
 }
 
-impl ApplicationCredentials {
-    async fn from_file<T: AsRef<Path>>(path: T) -> Result<ApplicationCredentials, Error> {
-        let content = fs::read_to_string(path)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, TEST_PRIVATE_KEY};
+    use crate::types::new_client;
+
+    fn credentials(token_uri: &str) -> ApplicationCredentials {
+        ApplicationCredentials {
+            r#type: Some("service_account".to_string()),
+            project_id: Some("project".to_string()),
+            private_key_id: Some("key-id".to_string()),
+            private_key: TEST_PRIVATE_KEY.to_string(),
+            client_email: "sa@project.iam.gserviceaccount.com".to_string(),
+            client_id: None,
+            auth_uri: None,
+            token_uri: token_uri.to_string(),
+            auth_provider_x509_cert_url: None,
+            client_x509_cert_url: None,
+            quota_project_id: None,
+            universe_domain: None,
+        }
+    }
+
+    /// Decodes header and claims of JWT without verifying the signature
+    fn decode_jwt(jwt: &str) -> (serde_json::Value, serde_json::Value) {
+        let parts: Vec<_> = jwt
+            .split('.')
+            .map(|part| base64::decode_config(part, base64::URL_SAFE).unwrap())
+            .collect();
+        assert_eq!(parts.len(), 3);
+        (
+            serde_json::from_slice(&parts[0]).unwrap(),
+            serde_json::from_slice(&parts[1]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn self_signed_jwt() {
+        let account = CustomServiceAccount::from_credentials(
+            credentials("https://oauth2.googleapis.com/token"),
+            &Endpoints::default(),
+        )
+        .unwrap();
+        let audience = "https://pubsub.googleapis.com/";
+        let token = account.get_self_signed_jwt(audience).unwrap();
+
+        let (header, claims) = decode_jwt(token.as_str());
+        assert_eq!(
+            header,
+            serde_json::json!({"alg": "RS256", "typ": "JWT", "kid": "key-id"})
+        );
+        assert_eq!(claims["aud"], audience);
+        assert_eq!(claims["iss"], "sa@project.iam.gserviceaccount.com");
+        assert_eq!(claims["sub"], "sa@project.iam.gserviceaccount.com");
+        assert!(claims.get("scope").is_none());
+        let exp = claims["exp"].as_i64().unwrap();
+        assert_eq!(token.expires_at(), Some(timestamp(exp).unwrap()));
+    }
+
+    #[test]
+    fn self_signed_jwt_cached_until_expiry() {
+        let account = CustomServiceAccount::from_credentials(
+            credentials("https://oauth2.googleapis.com/token"),
+            &Endpoints::default(),
+        )
+        .unwrap();
+        let audience = "https://pubsub.googleapis.com/";
+        let cached = Token::new(
+            "cached".to_string(),
+            Some(chrono::Utc::now() + chrono::Duration::minutes(30)),
+        );
+        account
+            .self_signed_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), cached);
+        assert_eq!(
+            account.get_self_signed_jwt(audience).unwrap().as_str(),
+            "cached"
+        );
+
+        // Tokens about to expire are signed again
+        let expiring = Token::new(
+            "expiring".to_string(),
+            Some(chrono::Utc::now() + chrono::Duration::seconds(5)),
+        );
+        account
+            .self_signed_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), expiring);
+        let token = account.get_self_signed_jwt(audience).unwrap();
+        assert_ne!(token.as_str(), "expiring");
+        assert_eq!(decode_jwt(token.as_str()).1["aud"], audience);
+        assert_eq!(
+            account.get_self_signed_jwt(audience).unwrap().as_str(),
+            token.as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_cached_per_subject_and_scopes() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", r#"{"access_token":"own","expires_in":3600}"#)),
+            Some((
+                "200 OK",
+                r#"{"access_token":"delegated","expires_in":3600}"#,
+            )),
+        ])
+        .await;
+        let account =
+            CustomServiceAccount::from_credentials(credentials(&uri), &Endpoints::default())
+                .unwrap();
+        let client = new_client();
+
+        let token = account.refresh_token(&client, &["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "own");
+        let token = account
+            .refresh_token_for_subject(&client, "user@example.com", &["scope-a"])
             .await
-            .map_err(Error::AplicationProfilePath)?;
-        Ok(serde_json::from_str(&content).map_err(Error::AplicationProfileFormat)?)
+            .unwrap();
+        assert_eq!(token.as_str(), "delegated");
+
+        assert_eq!(account.get_token(&["scope-a"]).unwrap().as_str(), "own");
+        assert_eq!(
+            account
+                .get_token_for_subject("user@example.com", &["scope-a"])
+                .unwrap()
+                .as_str(),
+            "delegated"
+        );
+        assert!(account
+            .get_token_for_subject("other@example.com", &["scope-a"])
+            .is_none());
+        assert!(account
+            .get_token_for_subject("user@example.com", &["scope-b"])
+            .is_none());
+
+        let requests = server.await.unwrap();
+        let assertion = |index: usize| decode_jwt(&requests[index].form()["assertion"]).1;
+        assert!(assertion(0).get("sub").is_none());
+        assert_eq!(assertion(1)["sub"], "user@example.com");
     }
 }
@@ -1,46 +1,66 @@
 use crate::authentication_manager::ServiceAccount;
+use crate::endpoints::Endpoints;
 use crate::prelude::*;
+use crate::revoke;
 use hyper::body::Body;
-use hyper::Method;
+use hyper::header;
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::io;
+use std::path::PathBuf;
 use std::sync::RwLock;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use url::form_urlencoded;
 
 #[derive(Debug)]
 pub struct DefaultAuthorizedUser {
-    token: RwLock<Token>,
+    credentials: RwLock<UserCredentials>,
+    token_uri: String,
+    file: Option<PathBuf>,
+    token: RwLock<Option<Token>>,
+    id_token: RwLock<Option<Token>>,
 }
 
 impl DefaultAuthorizedUser {
-    const DEFAULT_TOKEN_GCP_URI: &'static str = "https://accounts.google.com/o/oauth2/token";
-    const USER_CREDENTIALS_PATH: &'static str =
-        "/.config/gcloud/application_default_credentials.json";
-
-    pub async fn new(client: &HyperClient) -> Result<Self, Error> {
-        let token = RwLock::new(Self::get_token(client).await?);
-        Ok(Self { token })
-    }
-
-    fn build_token_request<T: serde::Serialize>(json: &T) -> Request<Body> {
-        Request::builder()
-            .method(Method::POST)
-            .uri(Self::DEFAULT_TOKEN_GCP_URI)
-            .header("content-type", "application/json")
-            .body(Body::from(serde_json::to_string(json).unwrap()))
-            .unwrap()
-    }
-
-    async fn get_token(client: &HyperClient) -> Result<Token, Error> {
-        log::debug!("Loading user credentials file");
-        let home = dirs_next::home_dir().ok_or(Error::NoHomeDir)?;
-        let cred =
-            UserCredentials::from_file(home.display().to_string() + Self::USER_CREDENTIALS_PATH)
-                .await?;
-        let req = Self::build_token_request(&RerfeshRequest {
-            client_id: cred.client_id,
-            client_secret: cred.client_secret,
-            grant_type: "refresh_token".to_string(),
-            refresh_token: cred.refresh_token,
-        });
+    /// Exchanges refresh token of the credentials for access token
+    ///
+    /// Refresh token rotated by the server is used for later refreshes and written back to `file`, if provided.
+    pub async fn from_credentials(
+        client: &HyperClient,
+        endpoints: &Endpoints,
+        credentials: UserCredentials,
+        file: Option<PathBuf>,
+    ) -> Result<Self, Error> {
+        endpoints.check_universe_domain(None)?;
+        let user = Self {
+            token_uri: endpoints.oauth_token_uri(credentials.token_uri.as_deref()),
+            credentials: RwLock::new(credentials),
+            file,
+            token: RwLock::new(None),
+            id_token: RwLock::new(None),
+        };
+        user.refresh(client).await?;
+        Ok(user)
+    }
+
+    async fn get_token(
+        client: &HyperClient,
+        token_uri: &str,
+        cred: &UserCredentials,
+    ) -> Result<RefreshResponse, Error> {
+        let rqbody = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&[
+                ("client_id", cred.client_id.as_str()),
+                ("client_secret", cred.client_secret.as_str()),
+                ("grant_type", "refresh_token"),
+                ("refresh_token", cred.refresh_token.as_str()),
+            ])
+            .finish();
+        let req = Request::post(token_uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(rqbody))
+            .unwrap();
         let token = client
             .request(req)
             .await
@@ -49,6 +69,82 @@ impl DefaultAuthorizedUser {
             .await?;
         Ok(token)
     }
+
+    /// Refreshes access token, keeping refresh token rotated by the server
+    async fn refresh(&self, client: &HyperClient) -> Result<RefreshResponse, Error> {
+        let credentials = self.credentials.read().unwrap().clone();
+        let response = Self::get_token(client, &self.token_uri, &credentials).await?;
+        let rotated = response
+            .refresh_token
+            .as_ref()
+            .filter(|refresh_token| **refresh_token != credentials.refresh_token);
+        if let Some(refresh_token) = rotated {
+            log::debug!("Refresh token of authorized user was rotated");
+            self.credentials.write().unwrap().refresh_token = refresh_token.clone();
+            if let Some(file) = self.file.as_ref() {
+                if let Err(err) = Self::write_refresh_token(file, refresh_token).await {
+                    log::warn!("Rotated refresh token could not be saved: {}", err);
+                }
+            }
+        }
+        *self.token.write().unwrap() = Some(response.token.clone());
+        Ok(response)
+    }
+
+    /// Replaces refresh token in the credentials file, keeping its other fields
+    async fn write_refresh_token(file: &Path, refresh_token: &str) -> Result<(), Error> {
+        let content = fs::read(file).await.map_err(Error::UserProfilePath)?;
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&content).map_err(Error::UserProfileFormat)?;
+        json["refresh_token"] = serde_json::Value::String(refresh_token.to_string());
+        let content = serde_json::to_vec_pretty(&json).map_err(Error::UserProfileFormat)?;
+        let permissions = fs::metadata(file)
+            .await
+            .map_err(Error::UserProfilePath)?
+            .permissions();
+        write_atomic(file, &content, Some(permissions))
+            .await
+            .map_err(Error::UserProfilePath)
+    }
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it over `path`
+///
+/// The temporary file is readable only by the owner on unix until `permissions` are applied.
+async fn write_atomic(
+    path: &Path,
+    content: &[u8],
+    permissions: Option<Permissions>,
+) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = path.with_file_name(tmp_name);
+
+    let result = async {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = fs::OpenOptions::from(options).open(&tmp).await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&tmp, permissions).await?;
+        }
+        fs::rename(&tmp, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    result
 }
 
 #[async_trait]
@@ -57,42 +153,233 @@ impl ServiceAccount for DefaultAuthorizedUser {
         Err(Error::NoProjectId)
     }
 
+    fn quota_project_id(&self) -> Option<String> {
+        self.credentials.read().unwrap().quota_project_id.clone()
+    }
+
     fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
-        Some(self.token.read().unwrap().clone())
+        self.token.read().unwrap().clone()
+    }
+
+    /// Revoking refresh token also revokes access tokens issued for it
+    async fn revoke(&self, client: &HyperClient, revoke_uri: &str) -> Result<(), Error> {
+        self.token.write().unwrap().take();
+        self.id_token.write().unwrap().take();
+        let refresh_token = self.credentials.read().unwrap().refresh_token.clone();
+        revoke::revoke_token(client, revoke_uri, &refresh_token).await
     }
 
     async fn refresh_token(&self, client: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
-        let token = Self::get_token(client).await?;
-        *self.token.write().unwrap() = token.clone();
+        Ok(self.refresh(client).await?.token)
+    }
+
+    fn get_id_token(&self, audience: &str) -> Option<Token> {
+        if audience != self.credentials.read().unwrap().client_id {
+            return None;
+        }
+        self.id_token.read().unwrap().clone()
+    }
+
+    /// User ID tokens are always issued for the OAuth client, so the audience must be the client ID
+    async fn refresh_id_token(&self, client: &HyperClient, audience: &str) -> Result<Token, Error> {
+        if audience != self.credentials.read().unwrap().client_id {
+            return Err(Error::UnsupportedAudience(audience.to_string()));
+        }
+        let response = self.refresh(client).await?;
+        let token = Token::from_jwt(response.id_token.ok_or(Error::NoIdToken)?)?;
+        *self.id_token.write().unwrap() = Some(token.clone());
         Ok(token)
     }
 }
 
-#[derive(Serialize, Debug)]
-struct RerfeshRequest {
-    client_id: String,
-    client_secret: String,
-    grant_type: String,
-    refresh_token: String,
+#[derive(Deserialize, Debug)]
+struct RefreshResponse {
+    #[serde(flatten)]
+    token: Token,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
 }
 
+/// Authorized user credentials, as stored in `application_default_credentials.json` by `gcloud auth`
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct UserCredentials {
+pub struct UserCredentials {
     /// Client id
     pub client_id: String,
     /// Client secret
     pub client_secret: String,
     /// Refresh Token
     pub refresh_token: String,
+    /// OAuth token endpoint, `https://oauth2.googleapis.com/token` when written by gcloud
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_uri: Option<String>,
+    /// Project used for quota and billing of requests
+    pub quota_project_id: Option<String>,
     /// Type
     pub r#type: String,
 }
 
 impl UserCredentials {
-    async fn from_file<T: AsRef<Path>>(path: T) -> Result<UserCredentials, Error> {
-        let content = fs::read_to_string(path)
+    /// Saves credentials in the format of application default credentials, usable with `GOOGLE_APPLICATION_CREDENTIALS`
+    ///
+    /// The file is readable only by the owner on unix, since the refresh token grants access to the account.
+    pub async fn save<T: AsRef<Path>>(&self, path: T) -> Result<(), Error> {
+        let content = serde_json::to_vec_pretty(self).map_err(Error::UserProfileFormat)?;
+        write_atomic(path.as_ref(), &content, None)
             .await
-            .map_err(Error::UserProfilePath)?;
-        Ok(serde_json::from_str(&content).map_err(Error::UserProfileFormat)?)
+            .map_err(Error::UserProfilePath)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+    use crate::types::new_client;
+
+    fn credentials(token_uri: &str) -> UserCredentials {
+        UserCredentials {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            refresh_token: "refresh-1".to_string(),
+            token_uri: Some(token_uri.to_string()),
+            quota_project_id: None,
+            r#type: "authorized_user".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rotated_refresh_token_is_used_and_written_back() {
+        let (uri, server) = serve(vec![
+            Some((
+                "200 OK",
+                r#"{"access_token":"access-1","expires_in":3600,"refresh_token":"refresh-2"}"#,
+            )),
+            Some(("200 OK", r#"{"access_token":"access-2","expires_in":3600}"#)),
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("gcp_auth_rotate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("credentials.json");
+        let credentials = credentials(&format!("{}/custom/token", uri));
+        std::fs::write(&file, serde_json::to_vec(&credentials).unwrap()).unwrap();
+
+        let client = new_client();
+        let user = DefaultAuthorizedUser::from_credentials(
+            &client,
+            &Endpoints::default(),
+            credentials,
+            Some(file.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(user.get_token(&[]).unwrap().as_str(), "access-1");
+        let token = user.refresh_token(&client, &[]).await.unwrap();
+        assert_eq!(token.as_str(), "access-2");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].line.starts_with("POST /custom/token "));
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("application/x-www-form-urlencoded")
+        );
+        let form = requests[0].form();
+        assert_eq!(form["grant_type"], "refresh_token");
+        assert_eq!(form["client_id"], "id");
+        assert_eq!(form["client_secret"], "secret");
+        assert_eq!(form["refresh_token"], "refresh-1");
+        assert_eq!(requests[1].form()["refresh_token"], "refresh-2");
+
+        let saved: UserCredentials =
+            serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(saved.refresh_token, "refresh-2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotated_refresh_token_without_file() {
+        let (uri, server) = serve(vec![
+            Some((
+                "200 OK",
+                r#"{"access_token":"access-1","expires_in":3600,"refresh_token":"refresh-2"}"#,
+            )),
+            Some(("200 OK", r#"{"access_token":"access-2","expires_in":3600}"#)),
+        ])
+        .await;
+        let client = new_client();
+        let user = DefaultAuthorizedUser::from_credentials(
+            &client,
+            &Endpoints::default(),
+            credentials(&uri),
+            None,
+        )
+        .await
+        .unwrap();
+        user.refresh_token(&client, &[]).await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[1].form()["refresh_token"], "refresh-2");
+        assert_eq!(user.credentials.read().unwrap().refresh_token, "refresh-2");
+    }
+
+    #[tokio::test]
+    async fn write_refresh_token_keeps_other_fields() {
+        let dir = std::env::temp_dir().join(format!("gcp_auth_user_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("application_default_credentials.json");
+        std::fs::write(
+            &file,
+            r#"{"client_id":"id","client_secret":"secret","refresh_token":"old","type":"authorized_user"}"#,
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+        }
+
+        DefaultAuthorizedUser::write_refresh_token(&file, "new")
+            .await
+            .unwrap();
+        let credentials: UserCredentials =
+            serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(credentials.refresh_token, "new");
+        assert_eq!(credentials.client_id, "id");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_restricts_permissions() {
+        let dir = std::env::temp_dir().join(format!("gcp_auth_save_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("credentials.json");
+        let credentials = UserCredentials {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            refresh_token: "refresh".to_string(),
+            token_uri: None,
+            quota_project_id: None,
+            r#type: "authorized_user".to_string(),
+        };
+        credentials.save(&file).await.unwrap();
+
+        let saved: UserCredentials =
+            serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(saved.refresh_token, "refresh");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
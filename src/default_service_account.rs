@@ -1,37 +1,88 @@
 use crate::authentication_manager::ServiceAccount;
+use crate::endpoints::Endpoints;
+use crate::metadata;
 use crate::prelude::*;
-use hyper::body::Body;
-use hyper::Method;
-use std::str;
+use hyper::StatusCode;
+use std::collections::HashSet;
 use std::sync::RwLock;
+use url::form_urlencoded;
 
 #[derive(Debug)]
 pub struct DefaultServiceAccount {
-    token: RwLock<Token>,
+    host: String,
+    account: String,
+    tokens: RwLock<HashMap<Vec<String>, Token>>,
+    rejected_scopes: RwLock<HashSet<Vec<String>>>,
+    id_tokens: RwLock<HashMap<String, Token>>,
 }
 
 impl DefaultServiceAccount {
-    const DEFAULT_PROJECT_ID_GCP_URI: &'static str =
-        "http://metadata.google.internal/computeMetadata/v1/project/project-id";
-    const DEFAULT_TOKEN_GCP_URI: &'static str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+    pub(crate) const DEFAULT_ACCOUNT: &'static str = "default";
+    const UNIVERSE_DOMAIN_PATH: &'static str = "universe/universe-domain";
 
-    pub async fn new(client: &HyperClient) -> Result<Self, Error> {
-        let token = RwLock::new(Self::get_token(client).await?);
-        Ok(Self { token })
+    /// Uses default service account of metadata server of `endpoints`
+    pub async fn new(client: &HyperClient, endpoints: &Endpoints) -> Result<Self, Error> {
+        Self::with_options(client, &endpoints.metadata_host(), Self::DEFAULT_ACCOUNT).await
     }
 
-    fn build_token_request(uri: &str) -> Request<Body> {
-        Request::builder()
-            .method(Method::GET)
-            .uri(uri)
-            .header("Metadata-Flavor", "Google")
-            .body(Body::empty())
-            .unwrap()
+    /// Uses service account `account` of metadata server at `host`, which may include port
+    ///
+    /// The account is identified by email or alias, such as `default`.
+    pub async fn with_options(
+        client: &HyperClient,
+        host: &str,
+        account: &str,
+    ) -> Result<Self, Error> {
+        let service_account = Self {
+            host: host.to_string(),
+            account: account.to_string(),
+            tokens: RwLock::new(HashMap::new()),
+            rejected_scopes: RwLock::new(HashSet::new()),
+            id_tokens: RwLock::new(HashMap::new()),
+        };
+        service_account.refresh_token(client, &[]).await?;
+        Ok(service_account)
     }
 
-    async fn get_token(client: &HyperClient) -> Result<Token, Error> {
-        log::debug!("Getting token from GCP instance metadata server");
-        let req = Self::build_token_request(Self::DEFAULT_TOKEN_GCP_URI);
+    fn account_path(&self, path: &str) -> String {
+        format!(
+            "{}{}/{}",
+            metadata::SERVICE_ACCOUNTS_PATH,
+            self.account,
+            path
+        )
+    }
+
+    /// Requests token with the scopes, `None` if the server doesn't support custom scopes
+    ///
+    /// Custom scopes are supported by GKE Workload Identity and Cloud Run but not on all environments,
+    /// other environments reject them with 400. The fallback is kept per scope set, since 400 may also be
+    /// caused by a malformed scope.
+    async fn fetch_scoped_token(
+        &self,
+        client: &HyperClient,
+        scopes: &[&str],
+    ) -> Result<Option<Token>, Error> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("scopes", &scopes.join(","))
+            .finish();
+        let path = format!("{}?{}", self.account_path("token"), query);
+        let req = metadata::build_request(&metadata::uri(&self.host, &path));
+        let rsp = client.request(req).await.map_err(Error::ConnectionError)?;
+        if rsp.status() == StatusCode::BAD_REQUEST {
+            log::warn!(
+                "Metadata server rejected scopes {:?}, using default scopes",
+                scopes
+            );
+            return Ok(None);
+        }
+        rsp.deserialize().await.map(Some)
+    }
+
+    /// Requests token with the default scopes of the account
+    async fn fetch_token(&self, client: &HyperClient) -> Result<Token, Error> {
+        let uri = metadata::uri(&self.host, &self.account_path("token"));
+        let req = metadata::build_request(&uri);
         let token = client
             .request(req)
             .await
@@ -44,28 +95,129 @@ impl DefaultServiceAccount {
 
 #[async_trait]
 impl ServiceAccount for DefaultServiceAccount {
-    async fn project_id(&self, client: &HyperClient) -> Result<String, Error> {
-        log::debug!("Getting project ID from GCP instance metadata server");
-        let req = Self::build_token_request(Self::DEFAULT_PROJECT_ID_GCP_URI);
-        let rsp = client.request(req).await.map_err(Error::ConnectionError)?;
+    /// Project ID of the metadata server is resolved as the last step by `AuthenticationManager`
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        Err(Error::NoProjectId)
+    }
 
-        let (_, body) = rsp.into_parts();
-        let body = hyper::body::to_bytes(body)
-            .await
-            .map_err(Error::ConnectionError)?;
-        match str::from_utf8(&body) {
-            Ok(s) => Ok(s.to_owned()),
-            Err(_) => Err(Error::ProjectIdNonUtf8),
+    /// Universe domain of the metadata server, environments without universe support use the default one
+    async fn universe_domain(&self, client: &HyperClient) -> Result<String, Error> {
+        match metadata::get_text(client, &self.host, Self::UNIVERSE_DOMAIN_PATH).await {
+            Ok(universe_domain) if !universe_domain.is_empty() => Ok(universe_domain),
+            Ok(_) | Err(Error::MetadataNotFound(_)) => {
+                Ok(Endpoints::DEFAULT_UNIVERSE_DOMAIN.to_string())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Token with default scopes is used for scopes once the server rejected them
+    fn get_token(&self, scopes: &[&str]) -> Option<Token> {
+        let mut key: Vec<_> = scopes.iter().map(|x| x.to_string()).collect();
+        if self.rejected_scopes.read().unwrap().contains(&key) {
+            key.clear();
+        }
+        self.tokens.read().unwrap().get(&key).cloned()
+    }
+
+    async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
+        log::debug!("Getting token from GCP instance metadata server");
+        let key: Vec<_> = scopes.iter().map(|x| (*x).to_string()).collect();
+        if !scopes.is_empty() && !self.rejected_scopes.read().unwrap().contains(&key) {
+            if let Some(token) = self.fetch_scoped_token(client, scopes).await? {
+                self.tokens.write().unwrap().insert(key, token.clone());
+                return Ok(token);
+            }
+            self.rejected_scopes.write().unwrap().insert(key);
         }
+        let token = self.fetch_token(client).await?;
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(Vec::new(), token.clone());
+        Ok(token)
     }
 
-    fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
-        Some(self.token.read().unwrap().clone())
+    fn get_id_token(&self, audience: &str) -> Option<Token> {
+        self.id_tokens.read().unwrap().get(audience).cloned()
     }
 
-    async fn refresh_token(&self, client: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
-        let token = Self::get_token(client).await?;
-        *self.token.write().unwrap() = token.clone();
+    async fn refresh_id_token(&self, client: &HyperClient, audience: &str) -> Result<Token, Error> {
+        log::debug!("Getting ID token from GCP instance metadata server");
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("audience", audience)
+            .append_pair("format", "full")
+            .finish();
+        let path = format!("{}?{}", self.account_path("identity"), query);
+        let jwt = metadata::get_text(client, &self.host, &path).await?;
+        let token = Token::from_jwt(jwt)?;
+        self.id_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), token.clone());
         Ok(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::serve;
+    use crate::AuthenticationManager;
+
+    #[tokio::test]
+    async fn scoped_tokens_with_fallback() {
+        let (uri, server) = serve(vec![
+            Some((
+                "200 OK",
+                r#"{"access_token":"default-1","expires_in":3600}"#,
+            )),
+            Some(("200 OK", r#"{"access_token":"scoped-a","expires_in":3600}"#)),
+            Some(("400 Bad Request", "invalid scope")),
+            Some((
+                "200 OK",
+                r#"{"access_token":"default-2","expires_in":3600}"#,
+            )),
+            Some(("200 OK", r#"{"access_token":"scoped-c","expires_in":3600}"#)),
+        ])
+        .await;
+        let host = uri.trim_start_matches("http://");
+        let manager = AuthenticationManager::from_metadata_host(host)
+            .await
+            .unwrap();
+
+        let token = manager.get_token(&["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "scoped-a");
+        let token = manager.get_token(&["scope-b"]).await.unwrap();
+        assert_eq!(token.as_str(), "default-2");
+
+        // Cached tokens, the rejected scope set keeps using the default scopes
+        let token = manager.get_token(&["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "scoped-a");
+        let token = manager.get_token(&["scope-b"]).await.unwrap();
+        assert_eq!(token.as_str(), "default-2");
+
+        // Other scope sets are still requested with custom scopes
+        let token = manager.get_token(&["scope-c"]).await.unwrap();
+        assert_eq!(token.as_str(), "scoped-c");
+
+        let requests = server.await.unwrap();
+        let targets: Vec<_> = requests
+            .iter()
+            .map(|request| request.line.split(' ').nth(1).unwrap())
+            .collect();
+        let token_path = "/computeMetadata/v1/instance/service-accounts/default/token";
+        assert_eq!(
+            targets,
+            vec![
+                token_path.to_string(),
+                format!("{}?scopes=scope-a", token_path),
+                format!("{}?scopes=scope-b", token_path),
+                token_path.to_string(),
+                format!("{}?scopes=scope-c", token_path),
+            ]
+        );
+        assert!(requests
+            .iter()
+            .all(|request| request.header("metadata-flavor") == Some("Google")));
+    }
+}
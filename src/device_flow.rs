@@ -0,0 +1,284 @@
+use crate::default_authorized_user::UserCredentials;
+use crate::prelude::*;
+use crate::types::new_client;
+use hyper::body::Body;
+use hyper::header;
+use std::time::{Duration, Instant};
+use url::form_urlencoded;
+
+/// OAuth 2.0 device authorization grant for signing in end users on machines without browser
+///
+/// The user opens the verification URL on another device and enters the user code, meanwhile the token
+/// endpoint is polled until the user consents.
+///
+/// ```async
+/// let flow = gcp_auth::DeviceFlow::new("client-id.apps.googleusercontent.com", "client-secret")
+///     .with_scopes(&["https://www.googleapis.com/auth/cloud-platform"]);
+/// let credentials = flow
+///     .authorize(|code| println!("Open {} and enter code {}", code.verification_url, code.user_code))
+///     .await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeviceFlow {
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    device_auth_uri: String,
+    token_uri: String,
+}
+
+impl DeviceFlow {
+    const DEFAULT_DEVICE_AUTH_URI: &'static str = "https://oauth2.googleapis.com/device/code";
+    const DEFAULT_TOKEN_URI: &'static str = "https://oauth2.googleapis.com/token";
+    const GRANT_TYPE: &'static str = "urn:ietf:params:oauth:grant-type:device_code";
+    const DEFAULT_INTERVAL: u64 = 5;
+    const SLOW_DOWN_INCREMENT: u64 = 5;
+
+    /// Creates flow for OAuth client of type "TVs and Limited Input devices"
+    pub fn new(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: Vec::new(),
+            device_auth_uri: Self::DEFAULT_DEVICE_AUTH_URI.to_string(),
+            token_uri: Self::DEFAULT_TOKEN_URI.to_string(),
+        }
+    }
+
+    /// Sets scopes the user is asked to consent to
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|x| (*x).to_string()).collect();
+        self
+    }
+
+    /// Overrides device authorization endpoint
+    pub fn with_device_auth_uri(mut self, uri: &str) -> Self {
+        self.device_auth_uri = uri.to_string();
+        self
+    }
+
+    /// Overrides token endpoint, which is also stored in the resulting credentials
+    pub fn with_token_uri(mut self, uri: &str) -> Self {
+        self.token_uri = uri.to_string();
+        self
+    }
+
+    /// Runs the flow, `present_code` is called with the verification URL and user code to be shown to the user
+    ///
+    /// Resolves after the user consents, fails with `Error::DeviceCodeExpired` if the user doesn't in time.
+    pub async fn authorize<F: FnOnce(&DeviceCode)>(
+        &self,
+        present_code: F,
+    ) -> Result<UserCredentials, Error> {
+        let client = new_client();
+        let rqbody = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&[
+                ("client_id", self.client_id.as_str()),
+                ("scope", self.scopes.join(" ").as_str()),
+            ])
+            .finish();
+        let code: DeviceCode = client
+            .request(Self::build_request(&self.device_auth_uri, rqbody))
+            .await
+            .map_err(Error::OAuthConnectionError)?
+            .deserialize()
+            .await?;
+        present_code(&code);
+
+        let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+        let mut interval = code.interval.unwrap_or(Self::DEFAULT_INTERVAL);
+        loop {
+            tokio::time::delay_for(Duration::from_secs(interval)).await;
+            if Instant::now() >= deadline {
+                return Err(Error::DeviceCodeExpired);
+            }
+            match self.poll(&client, &code.device_code).await {
+                Ok(PollResponse::Token(TokenResponse { refresh_token })) => {
+                    return Ok(UserCredentials {
+                        client_id: self.client_id.clone(),
+                        client_secret: self.client_secret.clone(),
+                        refresh_token: refresh_token.ok_or(Error::NoRefreshToken)?,
+                        token_uri: Some(self.token_uri.clone()),
+                        quota_project_id: None,
+                        r#type: "authorized_user".to_string(),
+                    })
+                }
+                Ok(PollResponse::Error(ErrorResponse { error })) => match error.as_str() {
+                    "authorization_pending" => {}
+                    "slow_down" => interval += Self::SLOW_DOWN_INCREMENT,
+                    "expired_token" => return Err(Error::DeviceCodeExpired),
+                    _ => return Err(Error::AuthorizationDenied(error)),
+                },
+                // Keep polling at the current interval, the user may still consent before expiry
+                Err(err @ Error::OAuthConnectionError(_))
+                | Err(err @ Error::ConnectionError(_)) => {
+                    log::warn!("Polling token endpoint failed, retrying: {}", err)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Polls token endpoint, pending authorization is reported as error response with status 400 or 428
+    async fn poll(&self, client: &HyperClient, device_code: &str) -> Result<PollResponse, Error> {
+        log::debug!("Polling token endpoint for device code");
+        let rqbody = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("device_code", device_code),
+                ("grant_type", Self::GRANT_TYPE),
+            ])
+            .finish();
+        let rsp = client
+            .request(Self::build_request(&self.token_uri, rqbody))
+            .await
+            .map_err(Error::OAuthConnectionError)?;
+        let (parts, body) = rsp.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(Error::ConnectionError)?;
+        if parts.status.is_success() {
+            let response = serde_json::from_slice(&body).map_err(Error::ParsingError)?;
+            return Ok(PollResponse::Token(response));
+        }
+        match serde_json::from_slice(&body) {
+            Ok(response) => Ok(PollResponse::Error(response)),
+            Err(_) => {
+                log::error!("Token endpoint responded with status {}", parts.status);
+                Err(Error::ServerUnavailable)
+            }
+        }
+    }
+
+    fn build_request(uri: &str, rqbody: String) -> Request<Body> {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(rqbody))
+            .unwrap()
+    }
+}
+
+/// Device code issued by the device authorization endpoint
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceCode {
+    /// Code identifying the device, used for polling
+    pub device_code: String,
+    /// Code the user enters on the verification page
+    pub user_code: String,
+    /// Page the user opens on another device
+    #[serde(alias = "verification_uri")]
+    pub verification_url: String,
+    /// Seconds until the codes expire
+    pub expires_in: u64,
+    /// Minimum seconds between polls
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug)]
+enum PollResponse {
+    Token(TokenResponse),
+    Error(ErrorResponse),
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, Recorded};
+
+    const DEVICE_CODE: &str = r#"{"device_code":"device","user_code":"ABC-DEF","verification_url":"https://www.google.com/device","expires_in":60,"interval":0}"#;
+
+    #[tokio::test]
+    async fn authorize_polls_until_consent() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", DEVICE_CODE)),
+            Some((
+                "428 Precondition Required",
+                r#"{"error":"authorization_pending"}"#,
+            )),
+            None,
+            Some((
+                "200 OK",
+                r#"{"access_token":"access","expires_in":3600,"refresh_token":"refresh"}"#,
+            )),
+        ])
+        .await;
+        let flow = DeviceFlow::new("client-id", "client-secret")
+            .with_device_auth_uri(&format!("{}/device/code", uri))
+            .with_token_uri(&format!("{}/token", uri));
+        let mut user_code = None;
+        let credentials = flow
+            .authorize(|code| user_code = Some(code.user_code.clone()))
+            .await
+            .unwrap();
+        assert_eq!(user_code.as_deref(), Some("ABC-DEF"));
+        assert_eq!(credentials.refresh_token, "refresh");
+
+        let requests: Vec<HashMap<String, String>> =
+            server.await.unwrap().iter().map(Recorded::form).collect();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0]["client_id"], "client-id");
+        for poll in &requests[1..] {
+            assert_eq!(poll["device_code"], "device");
+            assert_eq!(poll["grant_type"], DeviceFlow::GRANT_TYPE);
+        }
+    }
+
+    #[tokio::test]
+    async fn authorize_denied() {
+        let (uri, _server) = serve(vec![
+            Some(("200 OK", DEVICE_CODE)),
+            Some(("403 Forbidden", r#"{"error":"access_denied"}"#)),
+        ])
+        .await;
+        let flow = DeviceFlow::new("client-id", "client-secret")
+            .with_device_auth_uri(&uri)
+            .with_token_uri(&uri);
+        match flow.authorize(|_| {}).await {
+            Err(Error::AuthorizationDenied(error)) => assert_eq!(error, "access_denied"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn authorize_without_refresh_token() {
+        let (uri, _server) = serve(vec![
+            Some(("200 OK", DEVICE_CODE)),
+            Some(("200 OK", r#"{"access_token":"access","expires_in":3600}"#)),
+        ])
+        .await;
+        let flow = DeviceFlow::new("client-id", "client-secret")
+            .with_device_auth_uri(&uri)
+            .with_token_uri(&uri);
+        match flow.authorize(|_| {}).await {
+            Err(Error::NoRefreshToken) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn authorize_fails_on_server_error() {
+        let (uri, _server) = serve(vec![
+            Some(("200 OK", DEVICE_CODE)),
+            Some(("500 Internal Server Error", "internal error")),
+        ])
+        .await;
+        let flow = DeviceFlow::new("client-id", "client-secret")
+            .with_device_auth_uri(&uri)
+            .with_token_uri(&uri);
+        match flow.authorize(|_| {}).await {
+            Err(Error::ServerUnavailable) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use crate::metadata;
+use crate::prelude::*;
+
+/// Universe domain and endpoint overrides used for obtaining tokens
+///
+/// Endpoints are derived from the universe domain, `googleapis.com` by default. Each endpoint can be
+/// overridden, e.g. to use a Private Service Connect endpoint.
+///
+/// ```async
+/// let endpoints = gcp_auth::Endpoints::new()
+///     .with_universe_domain("example-universe.com")
+///     .with_oauth_token_uri("https://oauth2-myendpoint.p.example-universe.com/token");
+/// let authentication_manager = gcp_auth::init_with_endpoints(endpoints).await?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Endpoints {
+    universe_domain: Option<String>,
+    oauth_token_uri: Option<String>,
+    sts_uri: Option<String>,
+    iam_credentials_uri: Option<String>,
+    metadata_host: Option<String>,
+    revoke_uri: Option<String>,
+    tokeninfo_uri: Option<String>,
+}
+
+impl Endpoints {
+    pub(crate) const DEFAULT_UNIVERSE_DOMAIN: &'static str = "googleapis.com";
+    const DEFAULT_OAUTH_TOKEN_URI: &'static str = "https://accounts.google.com/o/oauth2/token";
+
+    /// Creates endpoints of the default universe
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the expected universe domain, credentials of other universes are rejected
+    pub fn with_universe_domain(mut self, universe_domain: &str) -> Self {
+        self.universe_domain = Some(universe_domain.to_string());
+        self
+    }
+
+    /// Overrides OAuth token endpoint of service account keys and user credentials
+    pub fn with_oauth_token_uri(mut self, uri: &str) -> Self {
+        self.oauth_token_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Overrides Security Token Service endpoint of workload identity federation
+    pub fn with_sts_uri(mut self, uri: &str) -> Self {
+        self.sts_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Overrides IAM Credentials API base URI, e.g. `https://iamcredentials.googleapis.com/v1`
+    pub fn with_iam_credentials_uri(mut self, uri: &str) -> Self {
+        self.iam_credentials_uri = Some(uri.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Overrides metadata server host, which takes precedence over `GCE_METADATA_HOST`
+    pub fn with_metadata_host(mut self, host: &str) -> Self {
+        self.metadata_host = Some(host.to_string());
+        self
+    }
+
+    /// Overrides OAuth token revocation endpoint
+    pub fn with_revoke_uri(mut self, uri: &str) -> Self {
+        self.revoke_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Overrides OAuth tokeninfo endpoint
+    pub fn with_tokeninfo_uri(mut self, uri: &str) -> Self {
+        self.tokeninfo_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Expected universe domain if set explicitly
+    pub fn universe_domain(&self) -> Option<&str> {
+        self.universe_domain.as_deref()
+    }
+
+    /// Rejects credentials of universe other than the expected one
+    pub(crate) fn check_universe_domain(&self, universe_domain: Option<&str>) -> Result<(), Error> {
+        let universe_domain = universe_domain.unwrap_or(Self::DEFAULT_UNIVERSE_DOMAIN);
+        match self.universe_domain.as_deref() {
+            Some(expected) if expected != universe_domain => Err(Error::UniverseDomainMismatch(
+                expected.to_string(),
+                universe_domain.to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Endpoints of the universe of credentials, which is used unless the universe is configured explicitly
+    pub(crate) fn for_universe(&self, universe_domain: Option<&str>) -> Result<Endpoints, Error> {
+        self.check_universe_domain(universe_domain)?;
+        let mut endpoints = self.clone();
+        if endpoints.universe_domain.is_none() {
+            endpoints.universe_domain = universe_domain.map(str::to_string);
+        }
+        Ok(endpoints)
+    }
+
+    fn universe(&self) -> &str {
+        self.universe_domain
+            .as_deref()
+            .unwrap_or(Self::DEFAULT_UNIVERSE_DOMAIN)
+    }
+
+    /// OAuth token endpoint, taking the URI of the credentials file if not overridden
+    pub(crate) fn oauth_token_uri(&self, credentials_uri: Option<&str>) -> String {
+        if let Some(uri) = self.oauth_token_uri.as_ref() {
+            return uri.clone();
+        }
+        match credentials_uri {
+            Some(uri) => uri.to_string(),
+            None if self.universe() == Self::DEFAULT_UNIVERSE_DOMAIN => {
+                Self::DEFAULT_OAUTH_TOKEN_URI.to_string()
+            }
+            None => format!("https://oauth2.{}/token", self.universe()),
+        }
+    }
+
+    /// Security Token Service endpoint, taking the URI of the credentials file if not overridden
+    pub(crate) fn sts_uri(&self, credentials_uri: &str) -> String {
+        self.sts_uri
+            .clone()
+            .unwrap_or_else(|| credentials_uri.to_string())
+    }
+
+    /// IAM Credentials API base URI
+    pub(crate) fn iam_credentials_uri(&self) -> String {
+        self.iam_credentials_uri
+            .clone()
+            .unwrap_or_else(|| format!("https://iamcredentials.{}/v1", self.universe()))
+    }
+
+    /// Rewrites IAM Credentials URI of the credentials file to the overridden endpoint
+    pub(crate) fn iam_credentials_resource_uri(&self, credentials_uri: &str) -> String {
+        match (
+            &self.iam_credentials_uri,
+            credentials_uri.find("/projects/"),
+        ) {
+            (Some(base), Some(index)) => format!("{}{}", base, &credentials_uri[index..]),
+            _ => credentials_uri.to_string(),
+        }
+    }
+
+    /// OAuth token revocation endpoint
+    pub(crate) fn revoke_uri(&self) -> String {
+        self.revoke_uri
+            .clone()
+            .unwrap_or_else(|| format!("https://oauth2.{}/revoke", self.universe()))
+    }
+
+    /// OAuth tokeninfo endpoint
+    pub(crate) fn tokeninfo_uri(&self) -> String {
+        self.tokeninfo_uri
+            .clone()
+            .unwrap_or_else(|| format!("https://oauth2.{}/tokeninfo", self.universe()))
+    }
+
+    pub(crate) fn metadata_host(&self) -> String {
+        self.metadata_host
+            .clone()
+            .unwrap_or_else(metadata::default_host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_universe() {
+        let endpoints = Endpoints::new();
+        assert_eq!(
+            endpoints.oauth_token_uri(None),
+            "https://accounts.google.com/o/oauth2/token"
+        );
+        assert_eq!(
+            endpoints.oauth_token_uri(Some("https://oauth2.googleapis.com/token")),
+            "https://oauth2.googleapis.com/token"
+        );
+        assert_eq!(
+            endpoints.iam_credentials_uri(),
+            "https://iamcredentials.googleapis.com/v1"
+        );
+        assert_eq!(
+            endpoints.sts_uri("https://sts.googleapis.com/v1/token"),
+            "https://sts.googleapis.com/v1/token"
+        );
+        assert_eq!(
+            endpoints.revoke_uri(),
+            "https://oauth2.googleapis.com/revoke"
+        );
+        assert_eq!(
+            endpoints.tokeninfo_uri(),
+            "https://oauth2.googleapis.com/tokeninfo"
+        );
+    }
+
+    #[test]
+    fn custom_universe() {
+        let endpoints = Endpoints::new().with_universe_domain("example-universe.com");
+        assert_eq!(
+            endpoints.oauth_token_uri(None),
+            "https://oauth2.example-universe.com/token"
+        );
+        assert_eq!(
+            endpoints.iam_credentials_uri(),
+            "https://iamcredentials.example-universe.com/v1"
+        );
+        assert_eq!(
+            endpoints.revoke_uri(),
+            "https://oauth2.example-universe.com/revoke"
+        );
+    }
+
+    #[test]
+    fn overridden_endpoints() {
+        let endpoints = Endpoints::new()
+            .with_oauth_token_uri("https://oauth2-psc.p.googleapis.com/token")
+            .with_sts_uri("https://sts-psc.p.googleapis.com/v1/token")
+            .with_iam_credentials_uri("https://iamcredentials-psc.p.googleapis.com/v1/");
+        assert_eq!(
+            endpoints.oauth_token_uri(Some("https://oauth2.googleapis.com/token")),
+            "https://oauth2-psc.p.googleapis.com/token"
+        );
+        assert_eq!(
+            endpoints.sts_uri("https://sts.googleapis.com/v1/token"),
+            "https://sts-psc.p.googleapis.com/v1/token"
+        );
+        assert_eq!(
+            endpoints.iam_credentials_resource_uri(
+                "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken"
+            ),
+            "https://iamcredentials-psc.p.googleapis.com/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken"
+        );
+        assert_eq!(
+            Endpoints::new().iam_credentials_resource_uri("https://example.com/token"),
+            "https://example.com/token"
+        );
+    }
+
+    #[test]
+    fn universe_domain_check() {
+        assert!(Endpoints::new().check_universe_domain(None).is_ok());
+        assert!(Endpoints::new()
+            .check_universe_domain(Some("example-universe.com"))
+            .is_ok());
+
+        let endpoints = Endpoints::new().with_universe_domain("example-universe.com");
+        assert!(endpoints
+            .check_universe_domain(Some("example-universe.com"))
+            .is_ok());
+        match endpoints.check_universe_domain(None) {
+            Err(Error::UniverseDomainMismatch(expected, actual)) => {
+                assert_eq!(expected, "example-universe.com");
+                assert_eq!(actual, "googleapis.com");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -9,10 +9,11 @@ pub enum Error {
     /// - Defaul service account - available inside GCP platform using GCP Instance Metadata server
     /// - Service account file - provided using `GOOGLE_APPLICATION_CREDENTIALS` with path
     ///
-    /// All authentication methods have been tested and none succeeded.
+    /// All authentication methods have been tested and none succeeded, errors of the methods are listed
+    /// in the order they were tried.
     /// Service account file can be donwloaded from GCP in json format.
     #[error("No available authentication method was discovered")]
-    NoAuthMethod(Box<Error>, Box<Error>, Box<Error>),
+    NoAuthMethod(Vec<Error>),
 
     /// Error in underlaying RustTLS library.
     /// Might signal problem with establishin secure connection using trusted certificates
@@ -47,10 +48,30 @@ pub enum Error {
     #[error("Application profile provided in `GOOGLE_APPLICATION_CREDENTIALS` was not parsable")]
     AplicationProfileFormat(serde_json::error::Error),
 
+    /// Credentials file has unsupported `type`
+    ///
+    /// Supported types are `service_account`, `authorized_user`, `external_account` and
+    /// `impersonated_service_account`.
+    #[error("Credentials type `{0}` is not supported")]
+    UnsupportedCredentialsType(String),
+
+    /// Credentials don't have the `type` field where it is required
+    ///
+    /// Source credentials of impersonated service account must have type `service_account` or `authorized_user`.
+    #[error("Credentials type is missing")]
+    MissingCredentialsType,
+
+    /// Universe domain of credentials differs from the expected one
+    ///
+    /// The first value is the expected universe domain, the second the universe domain of credentials.
+    #[error("Expected universe domain `{0}` but credentials belong to `{1}`")]
+    UniverseDomainMismatch(String, String),
+
     /// Default user profile not found
     ///
     /// User can authenticate locally during development using `gcloud auth login` which results in creating
-    /// `~/.config/gcloud/application_default_credentials.json` which couldn't be find on the machine
+    /// `application_default_credentials.json` in the gcloud configuration directory (`CLOUDSDK_CONFIG` or
+    /// `~/.config/gcloud`) which couldn't be find on the machine
     #[error("User authentication profile not found")]
     UserProfilePath(std::io::Error),
 
@@ -90,10 +111,141 @@ pub enum Error {
     #[error("Project ID not found through current authentication method")]
     ProjectIdNotFound,
 
+    /// ID token not supported for current authentication method
+    #[error("ID token not supported for current authentication method")]
+    NoIdToken,
+
+    /// ID token of authorized user can only be issued for its OAuth client, audience must be the client ID
+    #[error("ID token of authorized user can't be issued for audience `{0}`")]
+    UnsupportedAudience(String),
+
+    /// UNIX timestamp, such as expiry of a token, is out of range
+    #[error("Timestamp `{0}` is out of range")]
+    InvalidTimestamp(i64),
+
+    /// Self-signed JWT not supported for current authentication method
+    ///
+    /// Self-signed JWTs can only be created with service account key.
+    #[error("Self-signed JWT not supported for current authentication method")]
+    NoSelfSignedJwt,
+
+    /// Domain-wide delegation not supported for current authentication method
+    ///
+    /// Tokens on behalf of Workspace users can only be requested with service account key.
+    #[error("Domain-wide delegation not supported for current authentication method")]
+    NoSubject,
+
+    /// API key can't be exchanged for access token
+    ///
+    /// Use `AuthenticationManager::credential` or `AuthenticationManager::authorize_request` which handle both.
+    #[error("Access token not supported when authenticating with API key")]
+    NoToken,
+
+    /// Variable `GOOGLE_API_KEY` could not be found in the current environment
+    #[error("API key was not provided in `GOOGLE_API_KEY` env variable")]
+    ApiKeyMissing,
+
+    /// Access token file from `CLOUDSDK_AUTH_ACCESS_TOKEN_FILE` or gcloud configuration could not be read
+    #[error("Access token file could not be read")]
+    AccessTokenFile(std::io::Error),
+
+    /// Access token provided to the application has expired and can't be refreshed
+    #[error("Static access token has expired")]
+    StaticTokenExpired,
+
+    /// Loopback listener of installed application flow failed
+    #[error("Loopback listener for OAuth redirect failed")]
+    LoopbackError(std::io::Error),
+
+    /// Authorization endpoint of installed application flow is not a valid URL
+    #[error("Authorization URI is invalid")]
+    InvalidAuthUri,
+
+    /// User denied consent or authorization server returned error in the redirect
+    #[error("Authorization was denied: {0}")]
+    AuthorizationDenied(String),
+
+    /// Browser wasn't redirected back with authorization code before the flow timed out
+    #[error("Authorization timed out")]
+    AuthorizationTimeout,
+
+    /// Token endpoint didn't return refresh token for the authorization code
+    #[error("Refresh token was not returned")]
+    NoRefreshToken,
+
+    /// User didn't complete device authorization before the device code expired
+    #[error("Device code expired before authorization")]
+    DeviceCodeExpired,
+
+    /// Revocation endpoint rejected the token, with error code and description
+    ///
+    /// Rejections with `invalid_token`, i.e. of tokens which already expired or were revoked, aren't reported.
+    #[error("Token revocation rejected with {0}: {1}")]
+    RevocationRejected(String, String),
+
+    /// Tokens can't be revoked for current authentication method
+    #[error("Revocation not supported for current authentication method")]
+    NoRevocation,
+
+    /// Token issued with scope verification enabled doesn't grant the listed scopes
+    ///
+    /// Metadata server ignores scopes requested on environments which don't support custom scopes.
+    #[error("Token doesn't grant requested scopes {0:?}")]
+    ScopesNotGranted(Vec<String>),
+
+    /// Secure random generator of the system failed
+    #[error("Secure random generator unavailable")]
+    RandomUnavailable,
+
+    /// Metadata server has no value at the path
+    #[error("Metadata value `{0}` not found")]
+    MetadataNotFound(String),
+
+    /// Metadata value is invalid UTF-8
+    #[error("Metadata value is invalid UTF-8")]
+    MetadataNonUtf8,
+
     /// Project ID is invalid UTF-8
     #[error("Project ID is invalid UTF-8")]
     ProjectIdNonUtf8,
 
+    /// Subject token could not be found in the credential source of external account
+    ///
+    /// The credential source is either a file or a local URL which returns the token as text or as a JSON
+    /// object with the token stored in the `subject_token_field_name` field.
+    #[error("Subject token not found in credential source")]
+    SubjectTokenNotFound,
+
+    /// Credential source of external account is not supported
+    ///
+    /// Only file and URL sourced subject tokens are supported.
+    #[error("Credential source of external account is not supported")]
+    UnsupportedCredentialSource,
+
+    /// URL or headers of the credential source of external account are invalid
+    #[error("Credential source of external account is invalid: {0}")]
+    InvalidCredentialSource(String),
+
+    /// Subject token file of the credential source of external account could not be read
+    #[error("Subject token file could not be read")]
+    SubjectTokenFile(std::io::Error),
+
+    /// gcloud CLI could not be executed
+    #[error("gcloud command could not be executed")]
+    GCloudNotFound(std::io::Error),
+
+    /// gcloud CLI exited with error, e.g. when no account is logged in
+    #[error("gcloud command returned error")]
+    GCloudError,
+
+    /// Output of `gcloud config config-helper` was not parsable
+    #[error("gcloud output was not parsable")]
+    GCloudParseError(serde_json::error::Error),
+
+    /// Token or quota project can't be used as HTTP header value
+    #[error("Invalid HTTP header value")]
+    InvalidHeaderValue,
+
     /// Represents all other cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
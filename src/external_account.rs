@@ -0,0 +1,399 @@
+use crate::authentication_manager::ServiceAccount;
+use crate::endpoints::Endpoints;
+use crate::impersonated_service_account::ImpersonatedServiceAccount;
+use crate::prelude::*;
+use hyper::body::Body;
+use hyper::{header, Method};
+use std::sync::RwLock;
+use tokio::fs;
+use url::form_urlencoded;
+
+/// Workload identity federation account
+///
+/// Subject token of the external identity provider is exchanged for a Google access token at the
+/// Security Token Service.
+#[derive(Debug)]
+pub struct ExternalAccount {
+    tokens: RwLock<HashMap<Vec<String>, Token>>,
+    credentials: ExternalAccountCredentials,
+    token_url: String,
+}
+
+impl ExternalAccount {
+    const TOKEN_EXCHANGE_GRANT_TYPE: &'static str =
+        "urn:ietf:params:oauth:grant-type:token-exchange";
+    const ACCESS_TOKEN_TYPE: &'static str = "urn:ietf:params:oauth:token-type:access_token";
+    const CLOUD_PLATFORM_SCOPE: &'static str = "https://www.googleapis.com/auth/cloud-platform";
+
+    /// Wraps the account in impersonation if `service_account_impersonation_url` is provided
+    pub(crate) fn from_credentials(
+        credentials: ExternalAccountCredentials,
+        endpoints: &Endpoints,
+    ) -> Result<Box<dyn ServiceAccount>, Error> {
+        let endpoints = &endpoints.for_universe(credentials.universe_domain.as_deref())?;
+        let impersonation_url = credentials
+            .service_account_impersonation_url
+            .as_ref()
+            .map(|url| endpoints.iam_credentials_resource_uri(url));
+        let account = Self {
+            token_url: endpoints.sts_uri(&credentials.token_url),
+            credentials,
+            tokens: RwLock::new(HashMap::new()),
+        };
+        Ok(match impersonation_url {
+            Some(url) => Box::new(ImpersonatedServiceAccount::with_token_uri(
+                Box::new(account),
+                &url,
+                &[],
+                None,
+            )),
+            None => Box::new(account),
+        })
+    }
+
+    async fn subject_token(&self, client: &HyperClient) -> Result<String, Error> {
+        let source = &self.credentials.credential_source;
+        let content = if let Some(file) = &source.file {
+            log::debug!("Reading subject token from file");
+            fs::read_to_string(file)
+                .await
+                .map_err(Error::SubjectTokenFile)?
+        } else if let Some(url) = &source.url {
+            log::debug!("Requesting subject token from url");
+            let mut request = Request::builder().method(Method::GET).uri(url);
+            for (name, value) in &source.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            let request = request
+                .body(Body::empty())
+                .map_err(|err| Error::InvalidCredentialSource(err.to_string()))?;
+            let response = client
+                .request(request)
+                .await
+                .map_err(Error::ConnectionError)?;
+            if !response.status().is_success() {
+                log::error!("Subject token server responded with error");
+                return Err(Error::ServerUnavailable);
+            }
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(Error::ConnectionError)?;
+            String::from_utf8(body.to_vec()).map_err(|_| Error::SubjectTokenNotFound)?
+        } else {
+            return Err(Error::UnsupportedCredentialSource);
+        };
+        source.format.parse(&content)
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for ExternalAccount {
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        Err(Error::NoProjectId)
+    }
+
+    async fn universe_domain(&self, _: &HyperClient) -> Result<String, Error> {
+        Ok(self
+            .credentials
+            .universe_domain
+            .clone()
+            .unwrap_or_else(|| Endpoints::DEFAULT_UNIVERSE_DOMAIN.to_string()))
+    }
+
+    fn quota_project_id(&self) -> Option<String> {
+        self.credentials.quota_project_id.clone()
+    }
+
+    fn get_token(&self, scopes: &[&str]) -> Option<Token> {
+        let key: Vec<_> = scopes.iter().map(|x| x.to_string()).collect();
+        self.tokens.read().unwrap().get(&key).cloned()
+    }
+
+    async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
+        let subject_token = self.subject_token(client).await?;
+        let scope = if scopes.is_empty() {
+            Self::CLOUD_PLATFORM_SCOPE.to_string()
+        } else {
+            scopes.join(" ")
+        };
+        let rqbody = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&[
+                ("grant_type", Self::TOKEN_EXCHANGE_GRANT_TYPE),
+                ("audience", self.credentials.audience.as_str()),
+                ("scope", scope.as_str()),
+                ("requested_token_type", Self::ACCESS_TOKEN_TYPE),
+                ("subject_token", subject_token.as_str()),
+                (
+                    "subject_token_type",
+                    self.credentials.subject_token_type.as_str(),
+                ),
+            ])
+            .finish();
+        let request = Request::post(&self.token_url)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(rqbody))
+            .unwrap();
+        log::debug!(
+            "exchanging subject token at: {}",
+            self.credentials.token_url
+        );
+        let token = client
+            .request(request)
+            .await
+            .map_err(Error::OAuthConnectionError)?
+            .deserialize::<Token>()
+            .await?;
+        let key = scopes.iter().map(|x| (*x).to_string()).collect();
+        self.tokens.write().unwrap().insert(key, token.clone());
+        Ok(token)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountCredentials {
+    /// type
+    pub r#type: String,
+    /// audience
+    pub audience: String,
+    /// subject_token_type
+    pub subject_token_type: String,
+    /// token_url
+    pub token_url: String,
+    /// service_account_impersonation_url
+    pub service_account_impersonation_url: Option<String>,
+    /// credential_source
+    pub credential_source: CredentialSource,
+    /// quota_project_id
+    pub quota_project_id: Option<String>,
+    /// universe_domain
+    pub universe_domain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialSource {
+    /// file
+    pub file: Option<String>,
+    /// url
+    pub url: Option<String>,
+    /// headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// format
+    #[serde(default)]
+    pub format: CredentialSourceFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CredentialSourceFormat {
+    /// Subject token is the whole content of the source
+    Text,
+    /// Subject token is a field of JSON object
+    Json {
+        /// subject_token_field_name
+        subject_token_field_name: String,
+    },
+}
+
+impl Default for CredentialSourceFormat {
+    fn default() -> Self {
+        CredentialSourceFormat::Text
+    }
+}
+
+impl CredentialSourceFormat {
+    fn parse(&self, content: &str) -> Result<String, Error> {
+        let token = match self {
+            CredentialSourceFormat::Text => content.trim().to_string(),
+            CredentialSourceFormat::Json {
+                subject_token_field_name,
+            } => {
+                let value: serde_json::Value =
+                    serde_json::from_str(content).map_err(Error::ParsingError)?;
+                value
+                    .get(subject_token_field_name)
+                    .and_then(|token| token.as_str())
+                    .ok_or(Error::SubjectTokenNotFound)?
+                    .to_string()
+            }
+        };
+        if token.is_empty() {
+            return Err(Error::SubjectTokenNotFound);
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+    use crate::types::new_client;
+
+    fn external_credentials(
+        uri: &str,
+        impersonation_url: Option<String>,
+    ) -> ExternalAccountCredentials {
+        let json = serde_json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/provider",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/v1/token", uri),
+            "service_account_impersonation_url": impersonation_url,
+            "credential_source": {
+                "url": format!("{}/subject", uri),
+                "headers": {"Metadata": "True"},
+                "format": {"type": "json", "subject_token_field_name": "id_token"}
+            }
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exchange_subject_token() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", r#"{"id_token":"subject-jwt"}"#)),
+            Some((
+                "200 OK",
+                r#"{"access_token":"federated","expires_in":3600}"#,
+            )),
+        ])
+        .await;
+        let account = ExternalAccount::from_credentials(
+            external_credentials(&uri, None),
+            &Endpoints::default(),
+        )
+        .unwrap();
+        let token = account.refresh_token(&new_client(), &[]).await.unwrap();
+        assert_eq!(token.as_str(), "federated");
+        assert_eq!(account.get_token(&[]).unwrap().as_str(), "federated");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].line.starts_with("GET /subject "));
+        assert_eq!(requests[0].header("metadata"), Some("True"));
+        assert!(requests[1].line.starts_with("POST /v1/token "));
+        let form = requests[1].form();
+        assert_eq!(
+            form["grant_type"],
+            ExternalAccount::TOKEN_EXCHANGE_GRANT_TYPE
+        );
+        assert_eq!(
+            form["audience"],
+            "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/provider"
+        );
+        assert_eq!(form["scope"], ExternalAccount::CLOUD_PLATFORM_SCOPE);
+        assert_eq!(
+            form["requested_token_type"],
+            ExternalAccount::ACCESS_TOKEN_TYPE
+        );
+        assert_eq!(form["subject_token"], "subject-jwt");
+        assert_eq!(
+            form["subject_token_type"],
+            "urn:ietf:params:oauth:token-type:jwt"
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_with_impersonation() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", r#"{"id_token":"subject-jwt"}"#)),
+            Some((
+                "200 OK",
+                r#"{"access_token":"federated","expires_in":3600}"#,
+            )),
+            Some((
+                "200 OK",
+                r#"{"accessToken":"impersonated","expireTime":"2100-01-01T00:00:00Z"}"#,
+            )),
+        ])
+        .await;
+        let impersonation_url = format!(
+            "{}/v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken",
+            uri
+        );
+        let account = ExternalAccount::from_credentials(
+            external_credentials(&uri, Some(impersonation_url)),
+            &Endpoints::default(),
+        )
+        .unwrap();
+        let token = account
+            .refresh_token(&new_client(), &["scope-a"])
+            .await
+            .unwrap();
+        assert_eq!(token.as_str(), "impersonated");
+
+        let requests = server.await.unwrap();
+        assert_eq!(
+            requests[1].form()["scope"],
+            ExternalAccount::CLOUD_PLATFORM_SCOPE
+        );
+        assert!(requests[2].line.starts_with(
+            "POST /v1/projects/-/serviceAccounts/sa@project.iam.gserviceaccount.com:generateAccessToken "
+        ));
+        assert_eq!(
+            requests[2].header("authorization"),
+            Some("Bearer federated")
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_credential_source() {
+        let mut credentials = external_credentials("http://127.0.0.1:9", None);
+        credentials
+            .credential_source
+            .headers
+            .insert("Invalid Header".to_string(), "value".to_string());
+        let account =
+            ExternalAccount::from_credentials(credentials, &Endpoints::default()).unwrap();
+        match account.refresh_token(&new_client(), &[]).await {
+            Err(Error::InvalidCredentialSource(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut credentials = external_credentials("http://127.0.0.1:9", None);
+        credentials.credential_source.url = None;
+        credentials.credential_source.file = Some("/nonexistent/gcp_auth/token".to_string());
+        let account =
+            ExternalAccount::from_credentials(credentials, &Endpoints::default()).unwrap();
+        match account.refresh_token(&new_client(), &[]).await {
+            Err(Error::SubjectTokenFile(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_text_format() {
+        let format = CredentialSourceFormat::Text;
+        assert_eq!(format.parse("subject-token\n").unwrap(), "subject-token");
+        assert!(matches!(
+            format.parse("  \n"),
+            Err(Error::SubjectTokenNotFound)
+        ));
+    }
+
+    #[test]
+    fn parse_json_format() {
+        let format: CredentialSourceFormat =
+            serde_json::from_str(r#"{"type": "json", "subject_token_field_name": "access_token"}"#)
+                .unwrap();
+        let content = r#"{"access_token": "subject-token", "expires_in": 3600}"#;
+        assert_eq!(format.parse(content).unwrap(), "subject-token");
+        assert!(matches!(
+            format.parse(r#"{"id_token": "subject-token"}"#),
+            Err(Error::SubjectTokenNotFound)
+        ));
+        assert!(matches!(
+            format.parse("subject-token"),
+            Err(Error::ParsingError(_))
+        ));
+    }
+
+    #[test]
+    fn default_format_is_text() {
+        let source: CredentialSource =
+            serde_json::from_str(r#"{"file": "/var/run/token"}"#).unwrap();
+        assert!(matches!(source.format, CredentialSourceFormat::Text));
+    }
+}
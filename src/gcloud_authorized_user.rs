@@ -0,0 +1,168 @@
+use crate::authentication_manager::ServiceAccount;
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tokio::process::Command;
+
+/// Credentials of the account currently active in the gcloud CLI
+#[derive(Debug)]
+pub struct GCloudAuthorizedUser {
+    gcloud: PathBuf,
+    project_id: Option<String>,
+    token: RwLock<Token>,
+}
+
+impl GCloudAuthorizedUser {
+    const DEFAULT_GCLOUD_PATH: &'static str = "gcloud";
+
+    pub async fn new(gcloud: Option<&Path>) -> Result<Self, Error> {
+        let gcloud = gcloud
+            .map(|path| path.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from(Self::DEFAULT_GCLOUD_PATH));
+        let config = Self::config_helper(&gcloud).await?;
+        Ok(Self {
+            gcloud,
+            project_id: config.configuration.properties.core.project,
+            token: RwLock::new(config.credential.into_token()),
+        })
+    }
+
+    async fn config_helper(gcloud: &Path) -> Result<ConfigHelper, Error> {
+        log::debug!("Getting token from gcloud CLI");
+        let output = Command::new(gcloud)
+            .args(&["config", "config-helper", "--format=json"])
+            .output()
+            .await
+            .map_err(Error::GCloudNotFound)?;
+        if !output.status.success() {
+            log::error!(
+                "gcloud responded with error: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(Error::GCloudError);
+        }
+        serde_json::from_slice(&output.stdout).map_err(Error::GCloudParseError)
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for GCloudAuthorizedUser {
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        self.project_id.clone().ok_or(Error::ProjectIdNotFound)
+    }
+
+    fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+        Some(self.token.read().unwrap().clone())
+    }
+
+    async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+        let token = Self::config_helper(&self.gcloud)
+            .await?
+            .credential
+            .into_token();
+        *self.token.write().unwrap() = token.clone();
+        Ok(token)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfigHelper {
+    configuration: Configuration,
+    credential: Credential,
+}
+
+#[derive(Deserialize, Debug)]
+struct Configuration {
+    #[serde(default)]
+    properties: Properties,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Properties {
+    #[serde(default)]
+    core: CoreProperties,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct CoreProperties {
+    project: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Credential {
+    access_token: String,
+    token_expiry: Option<DateTime<Utc>>,
+}
+
+impl Credential {
+    fn into_token(self) -> Token {
+        Token::new(self.access_token, self.token_expiry)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::test_util::EnvGuard;
+    use crate::{init_with, Endpoints, GCloud};
+    use std::os::unix::fs::PermissionsExt;
+
+    const FAKE_GCLOUD: &str = r#"#!/bin/sh
+count_file="$(dirname "$0")/count"
+count=$(($(cat "$count_file" 2>/dev/null || echo 0) + 1))
+echo "$count" > "$count_file"
+cat <<JSON
+{
+  "configuration": {
+    "active_configuration": "default",
+    "properties": {"core": {"account": "user@example.com", "project": "test-project"}}
+  },
+  "credential": {"access_token": "token-$count", "token_expiry": "2000-01-01T00:00:00Z"}
+}
+JSON
+"#;
+
+    #[tokio::test]
+    async fn init_with_fake_gcloud() {
+        let dir = std::env::temp_dir().join(format!("gcp_auth_gcloud_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gcloud = dir.join("gcloud");
+        std::fs::write(&gcloud, FAKE_GCLOUD).unwrap();
+        std::fs::set_permissions(&gcloud, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Other methods fail fast so that the gcloud step is reached, variables are restored on drop
+        let mut env = EnvGuard::new();
+        env.set("CLOUDSDK_CONFIG", &dir);
+        for variable in &[
+            "GOOGLE_APPLICATION_CREDENTIALS",
+            "CLOUDSDK_AUTH_ACCESS_TOKEN",
+            "CLOUDSDK_AUTH_ACCESS_TOKEN_FILE",
+            "CLOUDSDK_ACTIVE_CONFIG_NAME",
+            "CLOUDSDK_CORE_PROJECT",
+            "GOOGLE_CLOUD_PROJECT",
+            "GCLOUD_PROJECT",
+        ] {
+            env.remove(variable);
+        }
+        let endpoints = Endpoints::new().with_metadata_host("127.0.0.1:9");
+
+        let authentication_manager = init_with(GCloud::Enabled(Some(&gcloud)), endpoints)
+            .await
+            .unwrap();
+        assert_eq!(
+            authentication_manager.project_id().await.unwrap(),
+            "test-project"
+        );
+
+        // Token obtained by init has already expired, so it is refreshed by running gcloud again
+        let token = authentication_manager.get_token(&[]).await.unwrap();
+        assert_eq!(token.as_str(), "token-2");
+        assert_eq!(
+            token.expires_at().unwrap().to_rfc3339(),
+            "2000-01-01T00:00:00+00:00"
+        );
+
+        drop(env);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use crate::prelude::*;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Properties of the active gcloud named configuration
+#[derive(Debug, Default, Clone)]
+pub struct GCloudConfig {
+    /// core/project
+    pub project: Option<String>,
+    /// auth/access_token_file
+    pub access_token_file: Option<String>,
+}
+
+impl GCloudConfig {
+    const CLOUDSDK_CONFIG: &'static str = "CLOUDSDK_CONFIG";
+    const CLOUDSDK_ACTIVE_CONFIG_NAME: &'static str = "CLOUDSDK_ACTIVE_CONFIG_NAME";
+    const CLOUDSDK_CORE_PROJECT: &'static str = "CLOUDSDK_CORE_PROJECT";
+    const CLOUDSDK_AUTH_ACCESS_TOKEN_FILE: &'static str = "CLOUDSDK_AUTH_ACCESS_TOKEN_FILE";
+    const DEFAULT_CONFIG_PATH: &'static str = ".config/gcloud";
+    const DEFAULT_CONFIG_NAME: &'static str = "default";
+
+    /// Directory of gcloud configuration, `CLOUDSDK_CONFIG` takes precedence over the home directory
+    pub fn config_dir() -> Result<PathBuf, Error> {
+        if let Ok(path) = std::env::var(Self::CLOUDSDK_CONFIG) {
+            return Ok(PathBuf::from(path));
+        }
+        let home = dirs_next::home_dir().ok_or(Error::NoHomeDir)?;
+        Ok(home.join(Self::DEFAULT_CONFIG_PATH))
+    }
+
+    /// Loads the active named configuration, missing configuration results in empty properties
+    ///
+    /// Properties can be overridden by `CLOUDSDK_<SECTION>_<PROPERTY>` environment variables as in gcloud.
+    pub async fn load() -> Result<Self, Error> {
+        let dir = Self::config_dir()?;
+        let name = Self::active_config_name(&dir).await;
+        log::debug!("Loading gcloud configuration {}", name);
+        let path = dir.join("configurations").join(format!("config_{}", name));
+        let mut config = match fs::read_to_string(path).await {
+            Ok(content) => Self::parse(&content),
+            Err(_) => Self::default(),
+        };
+        if let Ok(project) = std::env::var(Self::CLOUDSDK_CORE_PROJECT) {
+            config.project = Some(project);
+        }
+        if let Ok(access_token_file) = std::env::var(Self::CLOUDSDK_AUTH_ACCESS_TOKEN_FILE) {
+            config.access_token_file = Some(access_token_file);
+        }
+        Ok(config)
+    }
+
+    async fn active_config_name(dir: &Path) -> String {
+        if let Ok(name) = std::env::var(Self::CLOUDSDK_ACTIVE_CONFIG_NAME) {
+            return name;
+        }
+        fs::read_to_string(dir.join("active_config"))
+            .await
+            .map(|name| name.trim().to_string())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| Self::DEFAULT_CONFIG_NAME.to_string())
+    }
+
+    /// Parses supported properties of INI formatted configuration
+    fn parse(content: &str) -> Self {
+        let mut config = Self::default();
+        let mut section = "";
+        for line in content.lines().map(str::trim) {
+            if line.starts_with('[') && line.ends_with(']') {
+                section = &line[1..line.len() - 1];
+                continue;
+            }
+            let mut property = line.splitn(2, '=');
+            let (key, value) = match (property.next(), property.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim().to_string()),
+                _ => continue,
+            };
+            match (section, key) {
+                ("core", "project") => config.project = Some(value),
+                ("auth", "access_token_file") => config.access_token_file = Some(value),
+                _ => {}
+            }
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::EnvGuard;
+
+    #[test]
+    fn parse_properties() {
+        let config = GCloudConfig::parse(
+            "[core]\naccount = user@example.com\nproject = test-project\n\n[compute]\nproject = other\n",
+        );
+        assert_eq!(config.project.as_deref(), Some("test-project"));
+    }
+
+    #[test]
+    fn parse_ignores_other_sections_and_malformed_lines() {
+        let config =
+            GCloudConfig::parse("project = outside\n[compute]\nproject = other\n[core]\nproject\n");
+        assert_eq!(config.project, None);
+        assert_eq!(config.access_token_file, None);
+    }
+
+    #[tokio::test]
+    async fn active_config_name_from_file() {
+        let mut env = EnvGuard::new();
+        env.remove(GCloudConfig::CLOUDSDK_ACTIVE_CONFIG_NAME);
+        let dir = std::env::temp_dir().join(format!("gcp_auth_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(GCloudConfig::active_config_name(&dir).await, "default");
+
+        std::fs::write(dir.join("active_config"), "work\n").unwrap();
+        assert_eq!(GCloudConfig::active_config_name(&dir).await, "work");
+
+        std::fs::write(dir.join("active_config"), "\n").unwrap();
+        assert_eq!(GCloudConfig::active_config_name(&dir).await, "default");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::authentication_manager::ServiceAccount;
+use crate::endpoints::Endpoints;
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use hyper::body::Body;
+use hyper::{header, Method};
+use std::sync::RwLock;
+
+/// Service account impersonated through the IAM Credentials API
+///
+/// Access tokens for the target account are minted using tokens of the source credentials.
+pub struct ImpersonatedServiceAccount {
+    source: Box<dyn ServiceAccount>,
+    token_uri: String,
+    delegates: Vec<String>,
+    lifetime: Option<chrono::Duration>,
+    tokens: RwLock<HashMap<Vec<String>, Token>>,
+    id_tokens: RwLock<HashMap<String, Token>>,
+}
+
+impl ImpersonatedServiceAccount {
+    const CLOUD_PLATFORM_SCOPE: &'static str = "https://www.googleapis.com/auth/cloud-platform";
+
+    pub(crate) fn new(
+        source: Box<dyn ServiceAccount>,
+        endpoints: &Endpoints,
+        target_principal: &str,
+        delegates: &[&str],
+        lifetime: Option<chrono::Duration>,
+    ) -> Self {
+        let token_uri = format!(
+            "{}/projects/-/serviceAccounts/{}:generateAccessToken",
+            endpoints.iam_credentials_uri(),
+            target_principal
+        );
+        Self::with_token_uri(source, &token_uri, delegates, lifetime)
+    }
+
+    /// Creates impersonated account with full `generateAccessToken` URI, as found in credential files
+    pub(crate) fn with_token_uri(
+        source: Box<dyn ServiceAccount>,
+        token_uri: &str,
+        delegates: &[&str],
+        lifetime: Option<chrono::Duration>,
+    ) -> Self {
+        Self {
+            source,
+            token_uri: token_uri.to_string(),
+            delegates: delegates.iter().map(|x| x.to_string()).collect(),
+            lifetime,
+            tokens: RwLock::new(HashMap::new()),
+            id_tokens: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn build_token_request(&self, source_token: &Token, scopes: &[&str]) -> Request<Body> {
+        let body = GenerateAccessTokenRequest {
+            delegates: self.delegate_names(),
+            scope: scopes.iter().map(|x| x.to_string()).collect(),
+            lifetime: self
+                .lifetime
+                .map(|lifetime| format!("{}s", lifetime.num_seconds())),
+        };
+        Self::build_request(&self.token_uri, source_token, &body)
+    }
+
+    fn build_id_token_request(&self, source_token: &Token, audience: &str) -> Request<Body> {
+        let body = GenerateIdTokenRequest {
+            delegates: self.delegate_names(),
+            audience: audience.to_string(),
+            include_email: true,
+        };
+        let uri = self
+            .token_uri
+            .replace(":generateAccessToken", ":generateIdToken");
+        Self::build_request(&uri, source_token, &body)
+    }
+
+    /// Delegates can be given as emails or as full resource names
+    fn delegate_names(&self) -> Vec<String> {
+        self.delegates
+            .iter()
+            .map(|x| match x.starts_with("projects/") {
+                true => x.clone(),
+                false => format!("projects/-/serviceAccounts/{}", x),
+            })
+            .collect()
+    }
+
+    fn build_request<T: Serialize>(uri: &str, source_token: &Token, body: &T) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", source_token.as_str()),
+            )
+            .body(Body::from(serde_json::to_string(body).unwrap()))
+            .unwrap()
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for ImpersonatedServiceAccount {
+    async fn project_id(&self, client: &HyperClient) -> Result<String, Error> {
+        self.source.project_id(client).await
+    }
+
+    async fn universe_domain(&self, client: &HyperClient) -> Result<String, Error> {
+        self.source.universe_domain(client).await
+    }
+
+    fn quota_project_id(&self) -> Option<String> {
+        self.source.quota_project_id()
+    }
+
+    fn get_token(&self, scopes: &[&str]) -> Option<Token> {
+        let key: Vec<_> = scopes.iter().map(|x| x.to_string()).collect();
+        self.tokens.read().unwrap().get(&key).cloned()
+    }
+
+    async fn refresh_token(&self, client: &HyperClient, scopes: &[&str]) -> Result<Token, Error> {
+        let source_token = self
+            .source
+            .get_valid_token(client, &[Self::CLOUD_PLATFORM_SCOPE])
+            .await?;
+        let request = self.build_token_request(&source_token, scopes);
+        log::debug!(
+            "requesting token for impersonated service account: {}",
+            self.token_uri
+        );
+        let response: GenerateAccessTokenResponse = client
+            .request(request)
+            .await
+            .map_err(Error::OAuthConnectionError)?
+            .deserialize()
+            .await?;
+        let token = Token::new(response.access_token, Some(response.expire_time));
+        let key = scopes.iter().map(|x| (*x).to_string()).collect();
+        self.tokens.write().unwrap().insert(key, token.clone());
+        Ok(token)
+    }
+
+    fn get_id_token(&self, audience: &str) -> Option<Token> {
+        self.id_tokens.read().unwrap().get(audience).cloned()
+    }
+
+    async fn refresh_id_token(&self, client: &HyperClient, audience: &str) -> Result<Token, Error> {
+        let source_token = self
+            .source
+            .get_valid_token(client, &[Self::CLOUD_PLATFORM_SCOPE])
+            .await?;
+        let request = self.build_id_token_request(&source_token, audience);
+        let response: GenerateIdTokenResponse = client
+            .request(request)
+            .await
+            .map_err(Error::OAuthConnectionError)?
+            .deserialize()
+            .await?;
+        let token = Token::from_jwt(response.token)?;
+        self.id_tokens
+            .write()
+            .unwrap()
+            .insert(audience.to_string(), token.clone());
+        Ok(token)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct GenerateAccessTokenRequest {
+    delegates: Vec<String>,
+    scope: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lifetime: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerateIdTokenRequest {
+    delegates: Vec<String>,
+    audience: String,
+    include_email: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct GenerateIdTokenResponse {
+    token: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+    use crate::types::new_client;
+
+    /// Source credentials with fixed token
+    struct Source;
+
+    #[async_trait]
+    impl ServiceAccount for Source {
+        async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+            Err(Error::NoProjectId)
+        }
+
+        fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+            None
+        }
+
+        async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+            Ok(Token::new("source-token".to_string(), None))
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_access_token() {
+        let (uri, server) = serve(vec![
+            Some((
+                "200 OK",
+                r#"{"accessToken":"token-a","expireTime":"2100-01-01T00:00:00Z"}"#,
+            )),
+            Some((
+                "200 OK",
+                r#"{"accessToken":"token-b","expireTime":"2100-01-01T00:00:00Z"}"#,
+            )),
+        ])
+        .await;
+        let endpoints = Endpoints::default().with_iam_credentials_uri(&format!("{}/v1", uri));
+        let account = ImpersonatedServiceAccount::new(
+            Box::new(Source),
+            &endpoints,
+            "target@project.iam.gserviceaccount.com",
+            &[
+                "delegate@project.iam.gserviceaccount.com",
+                "projects/other/serviceAccounts/delegate@other.iam.gserviceaccount.com",
+            ],
+            Some(chrono::Duration::hours(1)),
+        );
+        let client = new_client();
+
+        let token = account.refresh_token(&client, &["scope-a"]).await.unwrap();
+        assert_eq!(token.as_str(), "token-a");
+        let token = account.refresh_token(&client, &["scope-b"]).await.unwrap();
+        assert_eq!(token.as_str(), "token-b");
+        assert_eq!(account.get_token(&["scope-a"]).unwrap().as_str(), "token-a");
+        assert_eq!(account.get_token(&["scope-b"]).unwrap().as_str(), "token-b");
+        assert!(account.get_token(&["scope-c"]).is_none());
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].line.starts_with(
+            "POST /v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken "
+        ));
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer source-token")
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "delegates": [
+                    "projects/-/serviceAccounts/delegate@project.iam.gserviceaccount.com",
+                    "projects/other/serviceAccounts/delegate@other.iam.gserviceaccount.com",
+                ],
+                "scope": ["scope-a"],
+                "lifetime": "3600s",
+            })
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["scope"], serde_json::json!(["scope-b"]));
+    }
+}
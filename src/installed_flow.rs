@@ -0,0 +1,385 @@
+use crate::default_authorized_user::UserCredentials;
+use crate::prelude::*;
+use crate::types::new_client;
+use hyper::body::Body;
+use hyper::header;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use url::{form_urlencoded, Url};
+
+/// OAuth 2.0 authorization code flow for installed applications signing in end users
+///
+/// Consent is given in the browser, which is redirected to a listener on the loopback interface.
+/// The authorization code is protected by PKCE and the redirect is checked with random state, requests
+/// with other state are answered and ignored. The flow fails if not completed within the timeout.
+///
+/// ```async
+/// let flow = gcp_auth::InstalledFlow::new("client-id.apps.googleusercontent.com", "client-secret")
+///     .with_scopes(&["https://www.googleapis.com/auth/cloud-platform"]);
+/// let credentials = flow.authorize(|url| println!("Open in browser: {}", url)).await?;
+/// credentials.save("credentials.json").await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct InstalledFlow {
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    auth_uri: String,
+    token_uri: String,
+    port: u16,
+    timeout: Duration,
+}
+
+impl InstalledFlow {
+    const DEFAULT_AUTH_URI: &'static str = "https://accounts.google.com/o/oauth2/auth";
+    const DEFAULT_TOKEN_URI: &'static str = "https://oauth2.googleapis.com/token";
+    const MAX_REQUEST_SIZE: usize = 8192;
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Creates flow for OAuth client of type "Desktop app"
+    pub fn new(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: Vec::new(),
+            auth_uri: Self::DEFAULT_AUTH_URI.to_string(),
+            token_uri: Self::DEFAULT_TOKEN_URI.to_string(),
+            port: 0,
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets scopes the user is asked to consent to
+    pub fn with_scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|x| (*x).to_string()).collect();
+        self
+    }
+
+    /// Overrides authorization endpoint
+    pub fn with_auth_uri(mut self, uri: &str) -> Self {
+        self.auth_uri = uri.to_string();
+        self
+    }
+
+    /// Overrides token endpoint, which is also stored in the resulting credentials
+    pub fn with_token_uri(mut self, uri: &str) -> Self {
+        self.token_uri = uri.to_string();
+        self
+    }
+
+    /// Sets port of the loopback listener, random free port is used by default
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets time the user has to complete the consent, 5 minutes by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the flow, `present_url` is called with the consent URL to be opened in the browser
+    ///
+    /// Resolves after the browser is redirected back with the authorization code and the code is
+    /// exchanged for refresh token.
+    pub async fn authorize<F: FnOnce(&str)>(
+        &self,
+        present_url: F,
+    ) -> Result<UserCredentials, Error> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, self.port));
+        let mut listener = TcpListener::bind(addr)
+            .await
+            .map_err(Error::LoopbackError)?;
+        let port = listener.local_addr().map_err(Error::LoopbackError)?.port();
+        let redirect_uri = format!("http://127.0.0.1:{}", port);
+
+        let state = random_string(16)?;
+        let code_verifier = random_string(32)?;
+        let code_challenge = base64::encode_config(
+            digest::digest(&digest::SHA256, code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let consent_url = Url::parse_with_params(
+            &self.auth_uri,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("scope", self.scopes.join(" ").as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+                ("access_type", "offline"),
+                ("prompt", "consent"),
+            ],
+        )
+        .map_err(|_| Error::InvalidAuthUri)?;
+        present_url(consent_url.as_str());
+
+        let code = timeout(self.timeout, Self::wait_for_code(&mut listener, &state))
+            .await
+            .map_err(|_| Error::AuthorizationTimeout)??;
+
+        log::debug!("Exchanging authorization code at {}", self.token_uri);
+        let rqbody = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&[
+                ("grant_type", "authorization_code"),
+                ("code", code.as_str()),
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("code_verifier", code_verifier.as_str()),
+            ])
+            .finish();
+        let request = Request::post(&self.token_uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(rqbody))
+            .unwrap();
+        let response: CodeResponse = new_client()
+            .request(request)
+            .await
+            .map_err(Error::OAuthConnectionError)?
+            .deserialize()
+            .await?;
+
+        Ok(UserCredentials {
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            refresh_token: response.refresh_token.ok_or(Error::NoRefreshToken)?,
+            token_uri: Some(self.token_uri.clone()),
+            quota_project_id: None,
+            r#type: "authorized_user".to_string(),
+        })
+    }
+
+    /// Accepts requests until the redirect with matching state arrives
+    async fn wait_for_code(listener: &mut TcpListener, state: &str) -> Result<String, Error> {
+        loop {
+            let (mut stream, _) = listener.accept().await.map_err(Error::LoopbackError)?;
+            let params = match Self::read_redirect(&mut stream).await {
+                Ok(Some(params)) => params,
+                // Browsers also request resources such as favicon
+                Ok(None) => {
+                    Self::respond(&mut stream, "404 Not Found", "Not found").await;
+                    continue;
+                }
+                Err(err) => {
+                    log::debug!("Request to loopback listener could not be read: {}", err);
+                    continue;
+                }
+            };
+            match Self::check_redirect(&params, state) {
+                Redirect::Code(code) => {
+                    let body = "Authorization succeeded, you can close this window.";
+                    Self::respond(&mut stream, "200 OK", body).await;
+                    return Ok(code);
+                }
+                Redirect::Denied(error) => {
+                    let body = "Authorization failed, you can close this window.";
+                    Self::respond(&mut stream, "200 OK", body).await;
+                    return Err(Error::AuthorizationDenied(error));
+                }
+                Redirect::Invalid => {
+                    log::debug!("Ignoring redirect without matching state or code");
+                    let body = "Invalid authorization response";
+                    Self::respond(&mut stream, "400 Bad Request", body).await;
+                }
+            }
+        }
+    }
+
+    /// Reads query parameters of the redirect, `None` for requests of other paths
+    async fn read_redirect(
+        stream: &mut TcpStream,
+    ) -> Result<Option<HashMap<String, String>>, Error> {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = timeout(Self::READ_TIMEOUT, stream.read(&mut buffer))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+                .and_then(|read| read)
+                .map_err(Error::LoopbackError)?;
+            if read == 0 || request.len() > Self::MAX_REQUEST_SIZE {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        // Request line has format `GET /?code=...&state=... HTTP/1.1`
+        let target = match request.lines().next().and_then(|x| x.split(' ').nth(1)) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let url = match Url::parse(&format!("http://127.0.0.1{}", target)) {
+            Ok(url) if url.path() == "/" => url,
+            _ => return Ok(None),
+        };
+        Ok(Some(url.query_pairs().into_owned().collect()))
+    }
+
+    /// Only redirects with matching state are trusted, others may not originate from the started flow
+    fn check_redirect(params: &HashMap<String, String>, state: &str) -> Redirect {
+        if params.get("state").map(String::as_str) != Some(state) {
+            return Redirect::Invalid;
+        }
+        if let Some(error) = params.get("error") {
+            return Redirect::Denied(error.clone());
+        }
+        match params.get("code") {
+            Some(code) => Redirect::Code(code.clone()),
+            None => Redirect::Invalid,
+        }
+    }
+
+    async fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if let Err(err) = stream.write_all(response.as_bytes()).await {
+            log::warn!("Response to browser could not be sent: {}", err);
+        }
+    }
+}
+
+/// Random URL-safe string of `len` bytes of entropy, used for state and PKCE code verifier
+fn random_string(len: usize) -> Result<String, Error> {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::RandomUnavailable)?;
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+enum Redirect {
+    Code(String),
+    Denied(String),
+    Invalid,
+}
+
+#[derive(Deserialize, Debug)]
+struct CodeResponse {
+    refresh_token: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+
+    /// Sends request to the loopback listener as the browser would, returning the response
+    async fn browse(redirect_uri: &str, target: &str) -> String {
+        let addr = redirect_uri.trim_start_matches("http://");
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn authorize_with_fake_server() {
+        let response = r#"{"access_token":"access","expires_in":3600,"refresh_token":"refresh"}"#;
+        let (uri, token_server) = serve(vec![Some(("200 OK", response))]).await;
+        let token_uri = format!("{}/token", uri);
+
+        let flow = InstalledFlow::new("client-id", "client-secret")
+            .with_scopes(&["scope-a", "scope-b"])
+            .with_auth_uri("https://auth.example.com/auth")
+            .with_token_uri(&token_uri);
+        let mut consent = HashMap::new();
+        let mut browser = None;
+        let credentials = flow
+            .authorize(|url| {
+                let url = Url::parse(url).unwrap();
+                assert_eq!(url.host_str(), Some("auth.example.com"));
+                consent = url.query_pairs().into_owned().collect();
+                let redirect_uri = consent["redirect_uri"].clone();
+                let state = consent["state"].clone();
+                browser = Some(tokio::spawn(async move {
+                    let favicon = browse(&redirect_uri, "/favicon.ico").await;
+                    let forged = browse(&redirect_uri, "/?code=forged&state=other").await;
+                    let missing = browse(&redirect_uri, &format!("/?state={}", state)).await;
+                    let target = format!("/?code=auth-code&state={}", state);
+                    let redirect = browse(&redirect_uri, &target).await;
+                    (favicon, forged, missing, redirect)
+                }));
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(consent["client_id"], "client-id");
+        assert_eq!(consent["scope"], "scope-a scope-b");
+        assert_eq!(consent["code_challenge_method"], "S256");
+        assert!(consent["redirect_uri"].starts_with("http://127.0.0.1:"));
+
+        let (favicon, forged, missing, redirect) = browser.unwrap().await.unwrap();
+        assert!(favicon.starts_with("HTTP/1.1 404"));
+        assert!(forged.starts_with("HTTP/1.1 400"));
+        assert!(missing.starts_with("HTTP/1.1 400"));
+        assert!(redirect.starts_with("HTTP/1.1 200"));
+
+        let request = token_server.await.unwrap().remove(0);
+        let params = request.form();
+        assert!(request.line.starts_with("POST /token "));
+        assert_eq!(params["grant_type"], "authorization_code");
+        assert_eq!(params["code"], "auth-code");
+        assert_eq!(params["client_secret"], "client-secret");
+        assert_eq!(params["redirect_uri"], consent["redirect_uri"]);
+        let challenge = base64::encode_config(
+            digest::digest(&digest::SHA256, params["code_verifier"].as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert_eq!(challenge, consent["code_challenge"]);
+
+        assert_eq!(credentials.refresh_token, "refresh");
+        assert_eq!(credentials.token_uri.as_deref(), Some(token_uri.as_str()));
+    }
+
+    #[tokio::test]
+    async fn authorize_denied() {
+        let flow = InstalledFlow::new("client-id", "client-secret");
+        let mut browser = None;
+        let result = flow
+            .authorize(|url| {
+                let consent: HashMap<String, String> = Url::parse(url)
+                    .unwrap()
+                    .query_pairs()
+                    .into_owned()
+                    .collect();
+                let redirect_uri = consent["redirect_uri"].clone();
+                let target = format!("/?error=access_denied&state={}", consent["state"]);
+                browser = Some(tokio::spawn(
+                    async move { browse(&redirect_uri, &target).await },
+                ));
+            })
+            .await;
+        match result {
+            Err(Error::AuthorizationDenied(error)) => assert_eq!(error, "access_denied"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(browser.unwrap().await.unwrap().starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn authorize_times_out() {
+        let flow = InstalledFlow::new("client-id", "client-secret")
+            .with_timeout(Duration::from_millis(100));
+        match flow.authorize(|_| {}).await {
+            Err(Error::AuthorizationTimeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -48,8 +48,12 @@ pub struct Claims<'a> {
     aud: &'a str,
     exp: i64,
     iat: i64,
+    #[serde(rename = "sub", skip_serializing_if = "Option::is_none")]
     subject: Option<&'a str>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_audience: Option<&'a str>,
 }
 
 impl<'a> Claims<'a> {
@@ -72,13 +76,34 @@ impl<'a> Claims<'a> {
             iat,
             subject,
             scope,
+            target_audience: None,
         }
     }
+
+    /// Claims of self-signed JWT used directly as access token for the API audience
+    pub fn with_audience(key: &'a ApplicationCredentials, audience: &'a str) -> Self {
+        let mut claims = Self::new::<&str>(key, &[], Some(key.client_email.as_str()));
+        claims.aud = audience;
+        claims
+    }
+
+    /// Expiry of the claims as UNIX timestamp
+    pub fn expiry(&self) -> i64 {
+        self.exp
+    }
+
+    /// Claims requesting ID token for the target audience instead of access token
+    pub fn with_target_audience(key: &'a ApplicationCredentials, target_audience: &'a str) -> Self {
+        let mut claims = Self::new::<&str>(key, &[], None);
+        claims.target_audience = Some(target_audience);
+        claims
+    }
 }
 
 /// A JSON Web Token ready for signing.
 pub(crate) struct JWTSigner {
     signer: Box<dyn rustls::sign::Signer>,
+    key_id: Option<String>,
 }
 
 impl JWTSigner {
@@ -88,11 +113,20 @@ impl JWTSigner {
         let signer = signing_key
             .choose_scheme(&[rustls::SignatureScheme::RSA_PKCS1_SHA256])
             .ok_or_else(|| Error::SignerSchemeError)?;
-        Ok(JWTSigner { signer })
+        Ok(JWTSigner {
+            signer,
+            key_id: None,
+        })
+    }
+
+    /// Sets `kid` header of signed tokens, required for self-signed JWTs
+    pub fn with_key_id(mut self, key_id: Option<&str>) -> Self {
+        self.key_id = key_id.map(|x| x.to_string());
+        self
     }
 
     pub fn sign_claims(&self, claims: &Claims) -> Result<String, rustls::TLSError> {
-        let mut jwt_head = Self::encode_claims(claims);
+        let mut jwt_head = self.encode_claims(claims);
         let signature = self.signer.sign(jwt_head.as_bytes())?;
         jwt_head.push_str(".");
         append_base64(&signature, &mut jwt_head);
@@ -101,11 +135,47 @@ impl JWTSigner {
 
     /// Encodes the first two parts (header and claims) to base64 and assembles them into a form
     /// ready to be signed.
-    fn encode_claims(claims: &Claims) -> String {
+    fn encode_claims(&self, claims: &Claims) -> String {
         let mut head = String::new();
-        append_base64(GOOGLE_RS256_HEAD, &mut head);
+        match &self.key_id {
+            Some(kid) => append_base64(
+                &format!(
+                    r#"{{"alg":"RS256","typ":"JWT","kid":{}}}"#,
+                    serde_json::json!(kid)
+                ),
+                &mut head,
+            ),
+            None => append_base64(GOOGLE_RS256_HEAD, &mut head),
+        }
         head.push_str(".");
         append_base64(&serde_json::to_string(&claims).unwrap(), &mut head);
         head
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TEST_PRIVATE_KEY;
+
+    #[test]
+    fn claims_with_subject() {
+        let key: ApplicationCredentials = serde_json::from_value(serde_json::json!({
+            "private_key": TEST_PRIVATE_KEY,
+            "client_email": "sa@project.iam.gserviceaccount.com",
+            "token_uri": "https://oauth2.googleapis.com/token",
+        }))
+        .unwrap();
+        let claims = Claims::new(&key, &["scope-a", "scope-b"], Some("user@example.com"));
+        let json = serde_json::to_value(&claims).unwrap();
+        assert_eq!(json["sub"], "user@example.com");
+        assert!(json.get("subject").is_none());
+        assert_eq!(json["iss"], "sa@project.iam.gserviceaccount.com");
+        assert_eq!(json["aud"], "https://oauth2.googleapis.com/token");
+        assert_eq!(json["scope"], "scope-a scope-b");
+
+        let claims = Claims::new(&key, &["scope-a"], None);
+        let json = serde_json::to_value(&claims).unwrap();
+        assert!(json.get("sub").is_none());
+    }
+}
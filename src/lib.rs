@@ -4,7 +4,9 @@
 //!
 //! 1. Path to service account JSON configuration file using GOOGLE_APPLICATION_CREDENTIALS environment
 //! variable. The service account configuration file can be downloaded in the IAM service when displaying service account detail.
-//! The downloaded JSON file should be provided without any further modification.
+//! The downloaded JSON file should be provided without any further modification. The variable can also point to
+//! `authorized_user`, `external_account` or `impersonated_service_account` credentials, the file is interpreted
+//! according to its `type` field.
 //! 2. Invoking the library inside GCP environment fetches the default service account for the service and
 //! the application is authenticated using that particular account
 //! 3. Application default credentials. Local user authetincation for development purposes created using `gcloud auth` application.
@@ -24,6 +26,13 @@
 //! let token = authentication_manager.get_token().await?;
 //! ```
 //!
+//! The metadata server host can be changed with `GCE_METADATA_HOST` or `GCE_METADATA_IP` environment variables,
+//! e.g. to use a metadata server emulator. Service accounts other than the default one can be selected with
+//! `AuthenticationManager::from_metadata_service_account`.
+//!
+//! Other values of the metadata server, such as the zone or custom attributes of the instance, are available through
+//! the `metadata` module using `AuthenticationManager::metadata`.
+//!
 //! # Custom service account
 //!
 //! When running outside of GCP e.g on development laptop to allow finer granularity for permission a
@@ -37,10 +46,177 @@
 //! let authentication_manager = gcp_auth::init().await?;
 //! let token = authentication_manager.get_token().await?;
 //! ```
+//! Service account keys can also sign JWTs locally which are accepted by Google APIs as Bearer tokens.
+//! This avoids the request to the OAuth token endpoint.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! let token = authentication_manager.get_self_signed_jwt("https://pubsub.googleapis.com/")?;
+//! ```
+//!
+//! Service account with domain-wide delegation can request tokens on behalf of Workspace users.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! let token = authentication_manager
+//!     .get_token_for_subject("user@example.com", &["https://www.googleapis.com/auth/gmail.readonly"])
+//!     .await?;
+//! ```
+//!
+//! Service account key can also be provided at run time, e.g. when stored in secrets store.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::AuthenticationManager::from_json(&service_account_key).await?;
+//! let token = authentication_manager.get_token(&["https://www.googleapis.com/auth/cloud-platform"]).await?;
+//! ```
+//!
 //! # Local user authentication
 //! This authentication method allows developers to authenticate again GCP services when developign locally.
 //! The method is intended only for development. Credentials can be set-up using `gcloud auth` utility.
-//! Credentials are read from file `~/.config/gcloud/application_default_credentials.json`.
+//! Credentials are read from file `application_default_credentials.json` in the gcloud configuration directory,
+//! which is `CLOUDSDK_CONFIG` if set and `~/.config/gcloud` otherwise. The project is taken from the active
+//! gcloud named configuration. A refresh token rotated by the server is kept only in memory by `init`, use
+//! `AuthenticationManager::from_user_credentials_file` to have it written back to the file.
+//!
+//! # Workload identity federation
+//!
+//! When `GOOGLE_APPLICATION_CREDENTIALS` points to an `external_account` configuration, the subject token
+//! is read from the configured file or local URL and exchanged for an access token at the Security Token Service.
+//! If `service_account_impersonation_url` is present, the exchanged token is used to impersonate the service account.
+//!
+//! # Quota project
+//!
+//! User credentials usually require the `x-goog-user-project` header with the project used for quota and billing.
+//! The quota project is taken from `quota_project_id` of the credentials and can be overridden using
+//! `GOOGLE_CLOUD_QUOTA_PROJECT` environment variable or `AuthenticationManager::with_quota_project`.
+//! Both headers can be set on a request at once.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! let mut request = hyper::Request::get("https://storage.googleapis.com/storage/v1/b?project=my-project")
+//!     .body(hyper::Body::empty())?;
+//! authentication_manager
+//!     .authorize_request(&mut request, &["https://www.googleapis.com/auth/devstorage.read_only"])
+//!     .await?;
+//! ```
+//!
+//! # Service account impersonation
+//!
+//! Any of the discovered credentials can be used to obtain tokens for another service account through the
+//! IAM Credentials API. The discovered identity needs the Service Account Token Creator role on the target account.
+//! Application default credentials created by `gcloud auth application-default login --impersonate-service-account`
+//! are supported as well.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init()
+//!     .await?
+//!     .impersonate("target@project.iam.gserviceaccount.com", &[], None);
+//! let token = authentication_manager.get_token(&["https://www.googleapis.com/auth/cloud-platform"]).await?;
+//! ```
+//!
+//! # gcloud CLI
+//!
+//! On developer machines the credentials of the account logged in to the gcloud CLI can be used by opting in
+//! with `init_with_gcloud`. Tokens are obtained by running `gcloud config config-helper --format=json`.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init_with_gcloud(None).await?;
+//! let token = authentication_manager.get_token(&[]).await?;
+//! ```
+//!
+//! # ID tokens
+//!
+//! Services such as Cloud Run or Identity-Aware Proxy require an OIDC ID token issued for a specific audience
+//! instead of an access token. ID tokens are cached per audience for their lifetime. Authorized users can only
+//! obtain ID tokens with the OAuth client ID as audience.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! let token = authentication_manager.get_id_token("https://my-service-abcdef-uc.a.run.app").await?;
+//! ```
+//!
+//! # Universe domain and endpoints
+//!
+//! Outside of the default `googleapis.com` universe, or behind a Private Service Connect endpoint, the
+//! token endpoints can be overridden. When the universe domain is configured, credentials belonging to
+//! another universe are rejected. Otherwise endpoints are derived from the `universe_domain` of the
+//! credentials file, while the metadata server and user credentials are assumed to be in `googleapis.com`.
+//!
+//! ```async
+//! let endpoints = gcp_auth::Endpoints::new()
+//!     .with_universe_domain("example-universe.com")
+//!     .with_sts_uri("https://sts-myendpoint.p.example-universe.com/v1/token");
+//! let authentication_manager = gcp_auth::init_with_endpoints(endpoints).await?;
+//! let universe_domain = authentication_manager.universe_domain().await?;
+//! ```
+//!
+//! # API keys
+//!
+//! Some APIs, such as Maps, accept API keys instead of OAuth tokens. Authentication manager created with API
+//! key provides it as `Credential::ApiKey`, so requests can be authorized without branching on the credential kind.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::AuthenticationManager::from_api_key_env()?;
+//! let credential = authentication_manager.credential(&[]).await?;
+//! let (name, value) = credential.header();
+//! ```
+//!
+//! # Access tokens provided externally
+//!
+//! Access token from `CLOUDSDK_AUTH_ACCESS_TOKEN`, or read from file given by `CLOUDSDK_AUTH_ACCESS_TOKEN_FILE`
+//! or the `auth/access_token_file` gcloud property, is used by `init` right after credentials given by
+//! GOOGLE_APPLICATION_CREDENTIALS. Such token can't be refreshed, but the file is re-read whenever it
+//! changes.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::AuthenticationManager::from_access_token_file("/var/run/token", None).await?;
+//! let token = authentication_manager.get_token(&[]).await?;
+//! ```
+//!
+//! # Signing in end users
+//!
+//! Command line tools can sign in users with `InstalledFlow`, which runs the OAuth authorization code flow
+//! with redirect to a loopback listener. The resulting credentials can be saved as application default credentials.
+//!
+//! ```async
+//! let credentials = gcp_auth::InstalledFlow::new("client-id.apps.googleusercontent.com", "client-secret")
+//!     .with_scopes(&["https://www.googleapis.com/auth/cloud-platform"])
+//!     .authorize(|url| println!("Open in browser: {}", url))
+//!     .await?;
+//! let authentication_manager = gcp_auth::AuthenticationManager::from_user_credentials(credentials).await?;
+//! ```
+//!
+//! On machines without browser, such as over SSH, `DeviceFlow` lets the user sign in on another device.
+//!
+//! ```async
+//! let credentials = gcp_auth::DeviceFlow::new("client-id.apps.googleusercontent.com", "client-secret")
+//!     .with_scopes(&["https://www.googleapis.com/auth/cloud-platform"])
+//!     .authorize(|code| println!("Open {} and enter code {}", code.verification_url, code.user_code))
+//!     .await?;
+//! let authentication_manager = gcp_auth::AuthenticationManager::from_user_credentials(credentials).await?;
+//! ```
+//!
+//! # Revocation
+//!
+//! Tokens of authorized user or service account key can be revoked, e.g. when the user logs out.
+//! Revoked tokens are purged from caches.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! authentication_manager.revoke().await?;
+//! ```
+//!
+//! # Token introspection
+//!
+//! Account, scopes and expiry of a token can be checked with the tokeninfo endpoint when requests fail
+//! with 403. In debug mode, every freshly issued token is checked to grant the requested scopes.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?.with_scope_verification();
+//! let token = authentication_manager.get_token(&["https://www.googleapis.com/auth/cloud-platform"]).await?;
+//! let info = authentication_manager.token_info(&token).await?;
+//! println!("{:?} expires at {:?}", info.email, info.expires_at);
+//! ```
 //!
 //! # FAQ
 //!
@@ -52,12 +228,27 @@
 #![deny(warnings)]
 #![allow(clippy::pedantic)]
 
+mod api_key;
 mod authentication_manager;
+mod credentials_file;
 mod custom_service_account;
 mod default_authorized_user;
 mod default_service_account;
+mod device_flow;
+mod endpoints;
 mod error;
+mod external_account;
+mod gcloud_authorized_user;
+mod gcloud_config;
+mod impersonated_service_account;
+mod installed_flow;
 mod jwt;
+pub mod metadata;
+mod revoke;
+mod static_token;
+#[cfg(test)]
+mod test_util;
+mod tokeninfo;
 mod types;
 mod util;
 mod prelude {
@@ -68,43 +259,124 @@ mod prelude {
     };
 }
 pub use authentication_manager::AuthenticationManager;
+pub use custom_service_account::ApplicationCredentials;
+pub use default_authorized_user::UserCredentials;
+pub use device_flow::{DeviceCode, DeviceFlow};
+pub use endpoints::Endpoints;
 pub use error::Error;
-pub use types::Token;
+pub use installed_flow::InstalledFlow;
+pub use metadata::MetadataServiceAccount;
+pub use tokeninfo::TokenInfo;
+pub use types::{Credential, ProjectIdSource, Token};
 
-use hyper::Client;
-use hyper_rustls::HttpsConnector;
+use authentication_manager::ServiceAccount;
+use std::path::Path;
+use types::HyperClient;
 
 /// Initialize GCP authentication
 ///
 /// Returns `AuthenticationManager` which can be used to obtain tokens
 pub async fn init() -> Result<AuthenticationManager, Error> {
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    init_with(GCloud::Disabled, Endpoints::default()).await
+}
+
+/// Initialize GCP authentication with credentials of the gcloud CLI as an additional method
+///
+/// Credentials of the account active in gcloud are tried before application default credentials.
+/// `gcloud` is the path to the gcloud binary, `gcloud` found on `PATH` is used when not provided.
+pub async fn init_with_gcloud(gcloud: Option<&Path>) -> Result<AuthenticationManager, Error> {
+    init_with(GCloud::Enabled(gcloud), Endpoints::default()).await
+}
+
+/// Initialize GCP authentication with universe domain and endpoint overrides
+///
+/// Credentials are discovered in the same way as by `init`, credentials of universe other than the one
+/// set in `endpoints` are rejected with `Error::UniverseDomainMismatch`.
+pub async fn init_with_endpoints(endpoints: Endpoints) -> Result<AuthenticationManager, Error> {
+    init_with(GCloud::Disabled, endpoints).await
+}
+
+/// Whether credentials of the gcloud CLI are tried by `init_with`, with optional path to the binary
+enum GCloud<'a> {
+    Disabled,
+    Enabled(Option<&'a Path>),
+}
+
+async fn init_with(
+    gcloud: GCloud<'_>,
+    endpoints: Endpoints,
+) -> Result<AuthenticationManager, Error> {
+    let client = types::new_client();
 
-    let custom = custom_service_account::CustomServiceAccount::new().await;
-    if let Ok(service_account) = custom {
-        return Ok(AuthenticationManager {
+    let mut errors = Vec::new();
+    let custom = credentials_file::from_env(&client, &endpoints).await;
+    if let Ok((service_account, endpoints)) = custom {
+        return Ok(AuthenticationManager::with_endpoints(
             client,
-            service_account: Box::new(service_account),
-        });
+            service_account,
+            endpoints,
+        ));
+    }
+    errors.extend(custom.err());
+    match static_token::StaticToken::from_env().await {
+        Ok(Some(token)) => {
+            return Ok(AuthenticationManager::with_endpoints(
+                client,
+                Box::new(token),
+                endpoints,
+            ))
+        }
+        Ok(None) => {}
+        Err(err) => errors.push(err),
     }
-    let default = default_service_account::DefaultServiceAccount::new(&client).await;
+    let default = default_service_account::DefaultServiceAccount::new(&client, &endpoints).await;
+    let default = match default {
+        Ok(service_account) => check_universe_domain(&client, &endpoints, service_account).await,
+        Err(err) => Err(err),
+    };
     if let Ok(service_account) = default {
-        return Ok(AuthenticationManager {
-            client: client.clone(),
-            service_account: Box::new(service_account),
-        });
+        return Ok(AuthenticationManager::with_endpoints(
+            client.clone(),
+            Box::new(service_account),
+            endpoints,
+        ));
+    }
+    errors.extend(default.err());
+    if let GCloud::Enabled(gcloud) = gcloud {
+        let gcloud = match endpoints.check_universe_domain(None) {
+            Ok(()) => gcloud_authorized_user::GCloudAuthorizedUser::new(gcloud).await,
+            Err(err) => Err(err),
+        };
+        if let Ok(user_account) = gcloud {
+            return Ok(AuthenticationManager::with_endpoints(
+                client,
+                Box::new(user_account),
+                endpoints,
+            ));
+        }
+        errors.extend(gcloud.err());
     }
-    let user = default_authorized_user::DefaultAuthorizedUser::new(&client).await;
-    if let Ok(user_account) = user {
-        return Ok(AuthenticationManager {
+    let user = credentials_file::from_well_known_file(&client, &endpoints).await;
+    if let Ok((user_account, endpoints)) = user {
+        return Ok(AuthenticationManager::with_endpoints(
             client,
-            service_account: Box::new(user_account),
-        });
+            user_account,
+            endpoints,
+        ));
+    }
+    errors.extend(user.err());
+    Err(Error::NoAuthMethod(errors))
+}
+
+/// Checks universe domain of the metadata server, only when the expected universe domain is configured
+async fn check_universe_domain<T: ServiceAccount>(
+    client: &HyperClient,
+    endpoints: &Endpoints,
+    service_account: T,
+) -> Result<T, Error> {
+    if endpoints.universe_domain().is_some() {
+        let universe_domain = service_account.universe_domain(client).await?;
+        endpoints.check_universe_domain(Some(&universe_domain))?;
     }
-    Err(Error::NoAuthMethod(
-        Box::new(custom.unwrap_err()),
-        Box::new(default.unwrap_err()),
-        Box::new(user.unwrap_err()),
-    ))
+    Ok(service_account)
 }
@@ -0,0 +1,390 @@
+//! Client of the GCP instance metadata server
+//!
+//! Provides information about the project and the instance the application is running on.
+//! Values which can't change during the lifetime of the instance are cached.
+//!
+//! ```async
+//! let authentication_manager = gcp_auth::init().await?;
+//! let zone = authentication_manager.metadata().instance_zone().await?;
+//! let flag = authentication_manager.metadata().attribute("feature-flag").await?;
+//! ```
+//!
+//! Changes of a value can be watched as a stream, which yields the current value first and then every change.
+//!
+//! ```async
+//! use futures_util::StreamExt;
+//!
+//! let authentication_manager = gcp_auth::init().await?;
+//! let mut changes = Box::pin(authentication_manager.metadata().watch("instance/attributes/feature-flag"));
+//! while let Some(value) = changes.next().await {
+//!     println!("feature-flag: {}", value?);
+//! }
+//! ```
+
+use crate::prelude::*;
+use futures_util::stream::{self, Stream};
+use hyper::body::Body;
+use hyper::{header, Method, StatusCode};
+use std::sync::RwLock;
+use std::time::Duration;
+use url::form_urlencoded;
+
+const GCE_METADATA_HOST: &str = "GCE_METADATA_HOST";
+const GCE_METADATA_IP: &str = "GCE_METADATA_IP";
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+pub(crate) const SERVICE_ACCOUNTS_PATH: &str = "instance/service-accounts/";
+
+/// Metadata server host from `GCE_METADATA_HOST` or `GCE_METADATA_IP`, `metadata.google.internal` by default
+pub(crate) fn default_host() -> String {
+    std::env::var(GCE_METADATA_HOST)
+        .or_else(|_| std::env::var(GCE_METADATA_IP))
+        .unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string())
+}
+
+pub(crate) fn uri(host: &str, path: &str) -> String {
+    format!("http://{}/computeMetadata/v1/{}", host, path)
+}
+
+pub(crate) fn build_request(uri: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Metadata-Flavor", "Google")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Requests metadata value at `path`, which may include query
+pub(crate) async fn get_text(
+    client: &HyperClient,
+    host: &str,
+    path: &str,
+) -> Result<String, Error> {
+    let req = build_request(&uri(host, path));
+    let rsp = client.request(req).await.map_err(Error::ConnectionError)?;
+    if rsp.status() == StatusCode::NOT_FOUND {
+        return Err(Error::MetadataNotFound(path.to_string()));
+    }
+    if !rsp.status().is_success() {
+        log::error!("Metadata server responded with error");
+        return Err(Error::ServerUnavailable);
+    }
+
+    let (_, body) = rsp.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(Error::ConnectionError)?;
+    String::from_utf8(body.to_vec()).map_err(|_| Error::MetadataNonUtf8)
+}
+
+/// Client of the metadata server sharing HTTP client with `AuthenticationManager`
+pub struct MetadataClient {
+    client: HyperClient,
+    host: String,
+    cache: RwLock<HashMap<String, String>>,
+}
+
+impl MetadataClient {
+    const PROJECT_ID_PATH: &'static str = "project/project-id";
+    const NUMERIC_PROJECT_ID_PATH: &'static str = "project/numeric-project-id";
+    const INSTANCE_ID_PATH: &'static str = "instance/id";
+    const INSTANCE_NAME_PATH: &'static str = "instance/name";
+    const INSTANCE_ZONE_PATH: &'static str = "instance/zone";
+    const WATCH_TIMEOUT_SEC: u64 = 300;
+    const WATCH_MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const WATCH_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    pub(crate) fn new(client: HyperClient, host: String) -> Self {
+        Self {
+            client,
+            host,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Requests raw metadata value at `path` relative to `computeMetadata/v1/`
+    pub async fn get(&self, path: &str) -> Result<String, Error> {
+        log::debug!("Getting {} from GCP instance metadata server", path);
+        get_text(&self.client, &self.host, path).await
+    }
+
+    async fn get_cached(&self, path: &str) -> Result<String, Error> {
+        let cached = self.cache.read().unwrap().get(path).cloned();
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+        let value = self.get(path).await?;
+        self.cache
+            .write()
+            .unwrap()
+            .insert(path.to_string(), value.clone());
+        Ok(value)
+    }
+
+    /// Project ID of the instance
+    pub async fn project_id(&self) -> Result<String, Error> {
+        match self.get_cached(Self::PROJECT_ID_PATH).await {
+            Err(Error::MetadataNonUtf8) => Err(Error::ProjectIdNonUtf8),
+            result => result,
+        }
+    }
+
+    /// Numeric project number of the instance
+    pub async fn numeric_project_id(&self) -> Result<String, Error> {
+        self.get_cached(Self::NUMERIC_PROJECT_ID_PATH).await
+    }
+
+    /// Numeric ID of the instance
+    pub async fn instance_id(&self) -> Result<String, Error> {
+        self.get_cached(Self::INSTANCE_ID_PATH).await
+    }
+
+    /// Name of the instance
+    pub async fn instance_name(&self) -> Result<String, Error> {
+        self.get_cached(Self::INSTANCE_NAME_PATH).await
+    }
+
+    /// Zone of the instance, e.g. `us-central1-a`
+    pub async fn instance_zone(&self) -> Result<String, Error> {
+        // Metadata server returns zone in format `projects/<number>/zones/<zone>`
+        let zone = self.get_cached(Self::INSTANCE_ZONE_PATH).await?;
+        Ok(zone.rsplit('/').next().unwrap_or_default().to_string())
+    }
+
+    /// Region of the instance derived from its zone, e.g. `us-central1`
+    pub async fn instance_region(&self) -> Result<String, Error> {
+        let zone = self.instance_zone().await?;
+        match zone.rfind('-') {
+            Some(index) => Ok(zone[..index].to_string()),
+            None => Ok(zone),
+        }
+    }
+
+    /// Custom attribute of the instance, `None` if the attribute is not defined
+    pub async fn attribute(&self, name: &str) -> Result<Option<String>, Error> {
+        self.optional(&format!("instance/attributes/{}", name))
+            .await
+    }
+
+    /// Custom attribute of the project, `None` if the attribute is not defined
+    pub async fn project_attribute(&self, name: &str) -> Result<Option<String>, Error> {
+        self.optional(&format!("project/attributes/{}", name)).await
+    }
+
+    async fn optional(&self, path: &str) -> Result<Option<String>, Error> {
+        match self.get(path).await {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::MetadataNotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Watches metadata value at `path` using wait-for-change long polling
+    ///
+    /// The stream yields the current value first and then each changed value. Connection errors and timeouts
+    /// are retried with exponential backoff, the stream ends after yielding an error for a value which is not
+    /// defined.
+    pub fn watch<'a>(&'a self, path: &'a str) -> impl Stream<Item = Result<String, Error>> + 'a {
+        let state = WatchState {
+            last: None,
+            backoff: Self::WATCH_MIN_BACKOFF,
+            done: false,
+        };
+        stream::unfold(state, move |mut state| async move {
+            if state.done {
+                return None;
+            }
+            loop {
+                let last_etag = state.last.as_ref().map(|(_, etag)| etag.as_str());
+                match self
+                    .wait_for_change(path, state.last.is_some(), last_etag)
+                    .await
+                {
+                    Ok((value, etag)) => {
+                        state.backoff = Self::WATCH_MIN_BACKOFF;
+                        // Server returns the unchanged value when the wait times out
+                        let unchanged = match state.last.as_ref() {
+                            Some((last, _)) if etag.is_empty() => *last == value,
+                            Some((_, last_etag)) => *last_etag == etag,
+                            None => false,
+                        };
+                        if unchanged {
+                            continue;
+                        }
+                        state.last = Some((value.clone(), etag));
+                        return Some((Ok(value), state));
+                    }
+                    Err(err @ Error::MetadataNotFound(_)) | Err(err @ Error::MetadataNonUtf8) => {
+                        state.done = true;
+                        return Some((Err(err), state));
+                    }
+                    Err(err) => {
+                        log::warn!("Watching {} failed, reconnecting: {}", path, err);
+                        tokio::time::delay_for(state.backoff).await;
+                        state.backoff = std::cmp::min(state.backoff * 2, Self::WATCH_MAX_BACKOFF);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Returns value with its ETag, waiting for change if `wait` is set
+    ///
+    /// Without `last_etag` the server waits for a change from the time of the request.
+    async fn wait_for_change(
+        &self,
+        path: &str,
+        wait: bool,
+        last_etag: Option<&str>,
+    ) -> Result<(String, String), Error> {
+        let mut uri = uri(&self.host, path);
+        if wait {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            query
+                .append_pair("wait_for_change", "true")
+                .append_pair("timeout_sec", &Self::WATCH_TIMEOUT_SEC.to_string());
+            if let Some(last_etag) = last_etag.filter(|etag| !etag.is_empty()) {
+                query.append_pair("last_etag", last_etag);
+            }
+            uri = format!("{}?{}", uri, query.finish());
+        }
+        let req = build_request(&uri);
+        // Leave the server enough time to respond after its own timeout
+        let timeout = Duration::from_secs(Self::WATCH_TIMEOUT_SEC + 10);
+        let rsp = tokio::time::timeout(timeout, self.client.request(req))
+            .await
+            .map_err(|_| Error::ServerUnavailable)?
+            .map_err(Error::ConnectionError)?;
+        if rsp.status() == StatusCode::NOT_FOUND {
+            return Err(Error::MetadataNotFound(path.to_string()));
+        }
+        if !rsp.status().is_success() {
+            log::error!("Metadata server responded with error");
+            return Err(Error::ServerUnavailable);
+        }
+
+        let etag = rsp
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let (_, body) = rsp.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(Error::ConnectionError)?;
+        let value = String::from_utf8(body.to_vec()).map_err(|_| Error::MetadataNonUtf8)?;
+        Ok((value, etag))
+    }
+
+    /// Lists service accounts attached to the instance with scopes granted to them
+    ///
+    /// Can be used to check at startup that the required scopes are available.
+    pub async fn service_accounts(&self) -> Result<Vec<MetadataServiceAccount>, Error> {
+        log::debug!("Listing service accounts of GCP instance metadata server");
+        let path = format!("{}?recursive=true", SERVICE_ACCOUNTS_PATH);
+        let req = build_request(&uri(&self.host, &path));
+        let accounts: HashMap<String, MetadataServiceAccount> = self
+            .client
+            .request(req)
+            .await
+            .map_err(Error::ConnectionError)?
+            .deserialize()
+            .await?;
+        // Every account is listed under its email and each of its aliases
+        let mut accounts: Vec<_> = accounts.into_iter().map(|(_, account)| account).collect();
+        accounts.sort_by(|a, b| a.email.cmp(&b.email));
+        accounts.dedup_by(|a, b| a.email == b.email);
+        Ok(accounts)
+    }
+}
+
+struct WatchState {
+    /// Last yielded value with its ETag
+    last: Option<(String, String)>,
+    backoff: Duration,
+    done: bool,
+}
+
+/// Service account attached to the instance, as listed by the metadata server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MetadataServiceAccount {
+    /// Email of the service account
+    pub email: String,
+    /// Aliases of the service account, such as `default`
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Scopes granted to the service account on the instance
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, Response};
+    use crate::types::new_client;
+    use futures_util::StreamExt;
+
+    fn value(body: &str, etag: &str) -> Option<Response> {
+        Some(Response::new("200 OK", body).with_header("ETag", etag))
+    }
+
+    #[tokio::test]
+    async fn watch_skips_unchanged_etag_and_reconnects() {
+        let (uri, server) = serve(vec![
+            value("v1", "etag-1"),
+            value("v1", "etag-1"),
+            None,
+            value("v2", "etag-2"),
+            Some(Response::new("404 Not Found", "")),
+        ])
+        .await;
+        let metadata = MetadataClient::new(new_client(), uri.trim_start_matches("http://").into());
+        let changes: Vec<_> = metadata.watch("instance/attributes/flag").collect().await;
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].as_ref().unwrap(), "v1");
+        assert_eq!(changes[1].as_ref().unwrap(), "v2");
+        match &changes[2] {
+            Err(Error::MetadataNotFound(path)) => assert_eq!(path, "instance/attributes/flag"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let requests = server.await.unwrap();
+        let path = "/computeMetadata/v1/instance/attributes/flag";
+        assert!(requests[0].line.starts_with(&format!("GET {} ", path)));
+        let waiting = format!(
+            "GET {}?wait_for_change=true&timeout_sec=300&last_etag=etag-1 ",
+            path
+        );
+        for request in &requests[1..4] {
+            assert!(request.line.starts_with(&waiting), "{}", request.line);
+        }
+        assert!(requests[4].line.contains("last_etag=etag-2"));
+    }
+
+    #[tokio::test]
+    async fn watch_compares_values_without_etag() {
+        let (uri, server) = serve(vec![
+            Some(("200 OK", "a")),
+            Some(("200 OK", "a")),
+            Some(("200 OK", "b")),
+        ])
+        .await;
+        let metadata = MetadataClient::new(new_client(), uri.trim_start_matches("http://").into());
+        let changes: Vec<_> = metadata
+            .watch("project/attributes/flag")
+            .take(2)
+            .collect()
+            .await;
+        let changes: Vec<_> = changes.into_iter().map(Result::unwrap).collect();
+        assert_eq!(changes, vec!["a", "b"]);
+
+        let requests = server.await.unwrap();
+        assert!(requests[1..]
+            .iter()
+            .all(|request| request.line.contains("wait_for_change=true")
+                && !request.line.contains("last_etag")));
+    }
+}
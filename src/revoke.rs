@@ -0,0 +1,117 @@
+use crate::prelude::*;
+use hyper::body::Body;
+use hyper::header;
+use url::form_urlencoded;
+
+/// Revokes access or refresh token at the revocation endpoint `revoke_uri`
+///
+/// Revoking refresh token also revokes access tokens issued for it. Tokens rejected with `invalid_token`
+/// already expired or were revoked, so they are considered revoked.
+pub(crate) async fn revoke_token(
+    client: &HyperClient,
+    revoke_uri: &str,
+    token: &str,
+) -> Result<(), Error> {
+    log::debug!("Revoking token at {}", revoke_uri);
+    let rqbody = form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", token)
+        .finish();
+    let request = Request::post(revoke_uri)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(rqbody))
+        .unwrap();
+    let rsp = client
+        .request(request)
+        .await
+        .map_err(Error::OAuthConnectionError)?;
+    if rsp.status().is_success() {
+        return Ok(());
+    }
+
+    let status = rsp.status();
+    let (_, body) = rsp.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(Error::OAuthConnectionError)?;
+    // Rejections have format `{"error": "invalid_token", "error_description": "Token expired or revoked"}`
+    match serde_json::from_slice::<RevokeError>(&body) {
+        Ok(rsp) if rsp.error == "invalid_token" => {
+            log::debug!("Token already expired or was revoked");
+            Ok(())
+        }
+        Ok(rsp) => Err(Error::RevocationRejected(
+            rsp.error,
+            rsp.error_description.unwrap_or_default(),
+        )),
+        Err(_) => Err(Error::RevocationRejected(
+            status.to_string(),
+            String::from_utf8_lossy(&body).into_owned(),
+        )),
+    }
+}
+
+/// Revokes all tokens that haven't expired yet, and returns the first error
+pub(crate) async fn revoke_tokens(
+    client: &HyperClient,
+    revoke_uri: &str,
+    tokens: Vec<Token>,
+) -> Result<(), Error> {
+    let mut result = Ok(());
+    for token in tokens.iter().filter(|token| !token.has_expired()) {
+        if let Err(err) = revoke_token(client, revoke_uri, token.as_str()).await {
+            log::warn!("Token could not be revoked: {}", err);
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+#[derive(Deserialize, Debug)]
+struct RevokeError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{serve, Recorded};
+    use crate::types::new_client;
+    use chrono::{Duration, Utc};
+
+    fn revoked_tokens(requests: Vec<Recorded>) -> Vec<String> {
+        requests
+            .iter()
+            .filter_map(|request| request.form().remove("token"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn invalid_token_is_already_revoked() {
+        let body = r#"{"error":"invalid_token","error_description":"Token expired or revoked"}"#;
+        let (uri, server) = serve(vec![Some(("400 Bad Request", body))]).await;
+        revoke_token(&new_client(), &uri, "token").await.unwrap();
+        assert_eq!(revoked_tokens(server.await.unwrap()), vec!["token"]);
+    }
+
+    #[tokio::test]
+    async fn rejection_is_reported() {
+        let body = r#"{"error":"unsupported_token_type"}"#;
+        let (uri, _server) = serve(vec![Some(("400 Bad Request", body))]).await;
+        match revoke_token(&new_client(), &uri, "token").await {
+            Err(Error::RevocationRejected(error, _)) => assert_eq!(error, "unsupported_token_type"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_skipped() {
+        let (uri, server) = serve(vec![Some(("200 OK", "{}"))]).await;
+        let tokens = vec![
+            Token::new("expired".to_string(), Some(Utc::now() - Duration::hours(1))),
+            Token::new("valid".to_string(), Some(Utc::now() + Duration::hours(1))),
+        ];
+        revoke_tokens(&new_client(), &uri, tokens).await.unwrap();
+        assert_eq!(revoked_tokens(server.await.unwrap()), vec!["valid"]);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::authentication_manager::ServiceAccount;
+use crate::gcloud_config::GCloudConfig;
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use tokio::fs;
+
+const CLOUDSDK_AUTH_ACCESS_TOKEN: &str = "CLOUDSDK_AUTH_ACCESS_TOKEN";
+const CLOUDSDK_AUTH_ACCESS_TOKEN_FILE: &str = "CLOUDSDK_AUTH_ACCESS_TOKEN_FILE";
+
+/// Access token obtained outside of the application, e.g. provided to CI jobs
+///
+/// Token read from file is re-read whenever modification time of the file changes.
+#[derive(Debug)]
+pub struct StaticToken {
+    path: Option<PathBuf>,
+    expires_at: Option<DateTime<Utc>>,
+    token: RwLock<(Option<SystemTime>, Token)>,
+}
+
+impl StaticToken {
+    pub(crate) fn new(access_token: &str, expires_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            path: None,
+            expires_at,
+            token: RwLock::new((None, Token::new(access_token.to_string(), expires_at))),
+        }
+    }
+
+    /// Reads token from file at `path`, `expires_at` applies to every token read from the file
+    pub(crate) async fn from_file(
+        path: PathBuf,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self, Error> {
+        let (modified, access_token) = Self::read(&path).await?;
+        Ok(Self {
+            path: Some(path),
+            expires_at,
+            token: RwLock::new((modified, Token::new(access_token, expires_at))),
+        })
+    }
+
+    /// Token from `CLOUDSDK_AUTH_ACCESS_TOKEN`, or file from `CLOUDSDK_AUTH_ACCESS_TOKEN_FILE` or
+    /// `auth/access_token_file` property of gcloud, `None` if none of them is set
+    pub(crate) async fn from_env() -> Result<Option<Self>, Error> {
+        if let Ok(access_token) = std::env::var(CLOUDSDK_AUTH_ACCESS_TOKEN) {
+            return Ok(Some(Self::new(&access_token, None)));
+        }
+        let path = match std::env::var(CLOUDSDK_AUTH_ACCESS_TOKEN_FILE) {
+            Ok(path) => Some(path),
+            Err(_) => GCloudConfig::load()
+                .await
+                .ok()
+                .and_then(|config| config.access_token_file),
+        };
+        match path {
+            Some(path) => Ok(Some(Self::from_file(path.into(), None).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn read(path: &Path) -> Result<(Option<SystemTime>, String), Error> {
+        let modified = fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let access_token = fs::read_to_string(path)
+            .await
+            .map_err(Error::AccessTokenFile)?;
+        Ok((modified, access_token.trim().to_string()))
+    }
+}
+
+#[async_trait]
+impl ServiceAccount for StaticToken {
+    async fn project_id(&self, _: &HyperClient) -> Result<String, Error> {
+        Err(Error::NoProjectId)
+    }
+
+    /// Token read from file is always checked for changes by `refresh_token`
+    fn get_token(&self, _scopes: &[&str]) -> Option<Token> {
+        match self.path {
+            Some(_) => None,
+            None => Some(self.token.read().unwrap().1.clone()),
+        }
+    }
+
+    /// Scopes are given by the token, static token can't be refreshed
+    async fn refresh_token(&self, _: &HyperClient, _scopes: &[&str]) -> Result<Token, Error> {
+        if let Some(path) = self.path.as_ref() {
+            let modified = fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            let cached = self.token.read().unwrap().0;
+            if modified.is_none() || modified != cached {
+                log::debug!("Reading access token from {}", path.display());
+                let (modified, access_token) = Self::read(path).await?;
+                *self.token.write().unwrap() =
+                    (modified, Token::new(access_token, self.expires_at));
+            }
+        }
+        let token = self.token.read().unwrap().1.clone();
+        if token.has_expired() {
+            return Err(Error::StaticTokenExpired);
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::new_client;
+
+    #[tokio::test]
+    async fn token_file_is_reread() {
+        let dir =
+            std::env::temp_dir().join(format!("gcp_auth_static_token_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access_token");
+        std::fs::write(&path, "first\n").unwrap();
+
+        let token = StaticToken::from_file(path.clone(), None).await.unwrap();
+        let client = new_client();
+        assert!(token.get_token(&[]).is_none());
+        assert_eq!(
+            token.refresh_token(&client, &[]).await.unwrap().as_str(),
+            "first"
+        );
+
+        std::fs::write(&path, "second\n").unwrap();
+        // Rewrite may keep the modification time on filesystems with coarse timestamps
+        token.token.write().unwrap().0 = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            token.refresh_token(&client, &[]).await.unwrap().as_str(),
+            "second"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_token() {
+        let client = new_client();
+        let token = StaticToken::new("token", Some(Utc::now() + chrono::Duration::hours(1)));
+        assert_eq!(
+            token.refresh_token(&client, &[]).await.unwrap().as_str(),
+            "token"
+        );
+
+        let token = StaticToken::new("token", Some(Utc::now() + chrono::Duration::seconds(10)));
+        match token.refresh_token(&client, &[]).await {
+            Err(Error::StaticTokenExpired) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::prelude::*;
+use crate::types::timestamp;
+use chrono::{DateTime, Utc};
+use url::form_urlencoded;
+
+/// Query parameter for introspecting access tokens
+pub(crate) const ACCESS_TOKEN: &str = "access_token";
+/// Query parameter for introspecting ID tokens
+pub(crate) const ID_TOKEN: &str = "id_token";
+
+/// Information about access or ID token returned by the tokeninfo endpoint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenInfo {
+    /// Email of the account the token belongs to, if the token carries the `email` scope
+    pub email: Option<String>,
+    /// Scopes granted to access token, empty for ID token
+    pub scopes: Vec<String>,
+    /// Audience of the token, OAuth client for access token
+    pub audience: Option<String>,
+    /// Expiry of the token
+    pub expires_at: Option<DateTime<Utc>>,
+    /// OAuth client the token was issued to
+    pub issued_to: Option<String>,
+}
+
+/// Requests information about the token at `tokeninfo_uri`, `kind` is `ACCESS_TOKEN` or `ID_TOKEN`
+///
+/// Intended for debugging, e.g. failures with 403, the endpoint shouldn't be called for every request.
+pub(crate) async fn token_info(
+    client: &HyperClient,
+    tokeninfo_uri: &str,
+    token: &Token,
+    kind: &str,
+) -> Result<TokenInfo, Error> {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair(kind, token.as_str())
+        .finish();
+    let request = Request::get(format!("{}?{}", tokeninfo_uri, query))
+        .body(hyper::Body::empty())
+        .unwrap();
+    let info: RawTokenInfo = client
+        .request(request)
+        .await
+        .map_err(Error::ConnectionError)?
+        .deserialize()
+        .await?;
+    Ok(TokenInfo {
+        email: info.email,
+        scopes: info
+            .scope
+            .map(|scope| scope.split(' ').map(str::to_string).collect())
+            .unwrap_or_default(),
+        audience: info.aud,
+        expires_at: info
+            .exp
+            .and_then(|exp| exp.parse().ok())
+            .map(timestamp)
+            .transpose()?,
+        issued_to: info.azp,
+    })
+}
+
+/// Tokeninfo response, numbers are returned as strings
+#[derive(Deserialize, Debug)]
+struct RawTokenInfo {
+    email: Option<String>,
+    scope: Option<String>,
+    aud: Option<String>,
+    exp: Option<String>,
+    #[serde(alias = "issued_to")]
+    azp: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+    use crate::types::new_client;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn access_token_info() {
+        let body = r#"{"azp":"client","aud":"client","scope":"scope-a scope-b","exp":"1600000000","expires_in":"3599","email":"user@example.com"}"#;
+        let (uri, server) = serve(vec![Some(("200 OK", body))]).await;
+        let token = Token::new("token".to_string(), None);
+        let info = token_info(
+            &new_client(),
+            &format!("{}/tokeninfo", uri),
+            &token,
+            ACCESS_TOKEN,
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.email.as_deref(), Some("user@example.com"));
+        assert_eq!(info.scopes, vec!["scope-a", "scope-b"]);
+        assert_eq!(info.issued_to.as_deref(), Some("client"));
+        assert_eq!(
+            info.expires_at,
+            Some(Utc.timestamp_opt(1600000000, 0).unwrap())
+        );
+
+        let request = server.await.unwrap().remove(0);
+        assert!(request
+            .line
+            .starts_with("GET /tokeninfo?access_token=token "));
+    }
+}
@@ -1,4 +1,5 @@
-use chrono::{DateTime, Utc};
+use crate::error::Error;
+use chrono::{DateTime, TimeZone, Utc};
 use serde::Deserializer;
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +16,28 @@ pub struct Token {
 }
 
 impl Token {
+    pub(crate) fn new(access_token: String, expires_at: Option<DateTime<Utc>>) -> Self {
+        Token {
+            access_token,
+            expires_at,
+        }
+    }
+
+    /// Creates token from a JWT, taking expiry from its `exp` claim
+    pub(crate) fn from_jwt(jwt: String) -> Result<Self, Error> {
+        let exp = jwt
+            .split('.')
+            .nth(1)
+            .and_then(|payload| base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|payload| serde_json::from_slice::<JwtExpiry>(&payload).ok())
+            .map(|claims| claims.exp);
+        let expires_at = match exp {
+            Some(exp) => Some(timestamp(exp)?),
+            None => None,
+        };
+        Ok(Token::new(jwt, expires_at))
+    }
+
     pub(crate) fn has_expired(&self) -> bool {
         self.expires_at
             .map(|expiration_time| expiration_time - chrono::Duration::seconds(30) <= Utc::now())
@@ -32,6 +55,18 @@ impl Token {
     }
 }
 
+/// Converts UNIX timestamp to time, failing for timestamps out of range
+pub(crate) fn timestamp(secs: i64) -> Result<DateTime<Utc>, Error> {
+    Utc.timestamp_opt(secs, 0)
+        .single()
+        .ok_or(Error::InvalidTimestamp(secs))
+}
+
+#[derive(Deserialize)]
+struct JwtExpiry {
+    exp: i64,
+}
+
 fn deserialize_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
 where
     D: Deserializer<'de>,
@@ -41,4 +76,89 @@ where
     Ok(s)
 }
 
+/// Credential attached to requests, depending on the authentication method
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Credential {
+    /// Access token sent as Bearer token in the `Authorization` header
+    Token(Token),
+    /// API key sent in the `x-goog-api-key` header or the `key` query parameter
+    ApiKey(String),
+}
+
+impl Credential {
+    /// Name and value of the header carrying the credential
+    pub fn header(&self) -> (&'static str, String) {
+        match self {
+            Credential::Token(token) => ("authorization", format!("Bearer {}", token.as_str())),
+            Credential::ApiKey(key) => ("x-goog-api-key", key.clone()),
+        }
+    }
+
+    /// Name and value of the query parameter carrying the credential, only API keys can be passed in query
+    pub fn query_pair(&self) -> Option<(&'static str, &str)> {
+        match self {
+            Credential::Token(_) => None,
+            Credential::ApiKey(key) => Some(("key", key)),
+        }
+    }
+}
+
+/// Source from which the project ID was resolved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProjectIdSource {
+    /// `GOOGLE_CLOUD_PROJECT` or `GCLOUD_PROJECT` environment variable
+    Environment,
+    /// Credentials, such as the `project_id` field of service account key
+    Credentials,
+    /// `quota_project_id` of application default credentials
+    QuotaProject,
+    /// `core/project` property of the active gcloud configuration
+    GCloudConfig,
+    /// GCP instance metadata server
+    MetadataServer,
+}
+
 pub type HyperClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+pub(crate) fn new_client() -> HyperClient {
+    let https = hyper_rustls::HttpsConnector::new();
+    hyper::Client::builder().build::<_, hyper::Body>(https)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(payload: &str) -> String {
+        format!(
+            "{}.{}.signature",
+            base64::encode_config(r#"{"alg":"RS256","typ":"JWT"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn from_jwt_reads_expiry() {
+        let token = Token::from_jwt(jwt(r#"{"aud":"audience","exp":1600000000}"#)).unwrap();
+        assert_eq!(
+            token.expires_at(),
+            Some(Utc.timestamp_opt(1600000000, 0).unwrap())
+        );
+        assert!(token.has_expired());
+    }
+
+    #[test]
+    fn from_jwt_without_expiry() {
+        let token = Token::from_jwt(jwt(r#"{"aud":"audience"}"#)).unwrap();
+        assert_eq!(token.expires_at(), None);
+        let token = Token::from_jwt("not-a-jwt".to_string()).unwrap();
+        assert_eq!(token.as_str(), "not-a-jwt");
+        assert_eq!(token.expires_at(), None);
+    }
+
+    #[test]
+    fn from_jwt_rejects_expiry_out_of_range() {
+        let result = Token::from_jwt(jwt(r#"{"exp":9223372036854775807}"#));
+        assert!(matches!(result, Err(Error::InvalidTimestamp(_))));
+    }
+}